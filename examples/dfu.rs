@@ -0,0 +1,71 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+// Firmware update with the library API: list the images, upload a new one,
+// mark it for test and reset the device.
+//
+//     cargo run --example dfu -- --serial /dev/ttyACM0 firmware.bin
+//
+// The device name "test" selects the built-in simulated device. It starts
+// fresh for every command and forgets the upload, so there the example
+// stops with "uploaded image not found".
+
+use anyhow::{bail, format_err, Result};
+use mcumgr_client::*;
+use std::path::PathBuf;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (device, filename) = match args.as_slice() {
+        [transport, device, filename] if transport == "--serial" => {
+            (device.clone(), PathBuf::from(filename))
+        }
+        [transport, ..] if transport == "--ble" => {
+            bail!("Bluetooth is not supported by this crate")
+        }
+        _ => bail!("usage: dfu --serial <device> <file>"),
+    };
+    let specs = SerialSpecs {
+        device,
+        ..Default::default()
+    };
+
+    // show the current images
+    let state = list(&specs)?;
+    for image in &state.images {
+        println!(
+            "slot {}: version {}, hash {}",
+            image.slot,
+            image.version,
+            hex::encode(&image.hash)
+        );
+    }
+
+    // upload to the secondary slot
+    upload(
+        &specs,
+        &UploadOptions::default(),
+        &filename,
+        Slot::Secondary,
+        Some(|offset, total| println!("{}/{} bytes", offset, total)),
+    )?;
+
+    // mark the new image for test, then boot it
+    let state = list(&specs)?;
+    if state.has_duplicate_hashes() {
+        // select the copy by slot, not by hash
+        println!("warning: the same image is in more than one slot, testing the one in slot 1");
+    }
+    let image = state
+        .images
+        .iter()
+        .find(|image| image.slot == 1)
+        .ok_or_else(|| format_err!("uploaded image not found in slot 1"))?;
+    test(&specs, image.hash.clone(), None)?;
+    reset(&specs)?;
+
+    println!(
+        "image {} will be tested after reset",
+        hex::encode(&image.hash)
+    );
+    Ok(())
+}
@@ -0,0 +1,345 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::default::{exit_recovery, reset, safe_reset};
+use crate::image::{
+    confirm_slot, erase, erase_all, estimate_upload, flash_area_info, image_hashes, in_recovery,
+    list, mark_confirmed, running_image_hash, slot_of_hash, slot_of_version, test, upload,
+    upload_events_then, FlashArea, Slot, SlotResults, UploadEstimate, UploadOptions,
+    UploadProgress, UploadStats,
+};
+use crate::nmp_hdr::ImageStateRsp;
+use crate::probe::{capabilities, detect_context, probe, Capabilities, DeviceContext, DeviceProbe};
+use crate::transfer::SerialSpecs;
+
+/// transport used to talk to the device
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Serial(SerialSpecs),
+}
+
+/// runs the commands over whichever transport it was created with
+#[derive(Debug)]
+pub struct Client {
+    transport: Transport,
+    cache_ttl: Option<Duration>,
+    cached_state: Arc<StateCache>,
+}
+
+type StateCache = Mutex<Option<(Instant, ImageStateRsp)>>;
+
+impl Clone for Client {
+    fn clone(&self) -> Client {
+        Client {
+            transport: self.transport.clone(),
+            cache_ttl: self.cache_ttl,
+            cached_state: Arc::new(Mutex::new(self.cached())),
+        }
+    }
+}
+
+impl Client {
+    pub fn serial(specs: SerialSpecs) -> Client {
+        Client {
+            transport: Transport::Serial(specs),
+            cache_ttl: None,
+            cached_state: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Reuse the image list for `ttl`, instead of reading it again on each
+    /// `list`. The cache is dropped when a command changing the images has
+    /// finished, whether it succeeded or not.
+    pub fn with_cache(mut self, ttl: Duration) -> Client {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// image list, from the cache if enabled and still valid
+    pub fn list(&self) -> Result<ImageStateRsp, Error> {
+        if let (Some(ttl), Some((read_at, state))) = (self.cache_ttl, self.cached()) {
+            if read_at.elapsed() < ttl {
+                return Ok(state);
+            }
+        }
+        self.refresh()
+    }
+
+    /// read the image list from the device, and cache it if enabled
+    pub fn refresh(&self) -> Result<ImageStateRsp, Error> {
+        self.invalidate();
+        let state = match &self.transport {
+            Transport::Serial(specs) => list(specs)?,
+        };
+        if self.cache_ttl.is_some() {
+            *self.state_cache() = Some((Instant::now(), state.clone()));
+        }
+        Ok(state)
+    }
+
+    fn state_cache(&self) -> MutexGuard<'_, Option<(Instant, ImageStateRsp)>> {
+        lock_cache(&self.cached_state)
+    }
+
+    fn cached(&self) -> Option<(Instant, ImageStateRsp)> {
+        self.state_cache().clone()
+    }
+
+    fn invalidate(&self) {
+        self.state_cache().take();
+    }
+
+    // Run a command changing the images, then drop the cache. Dropping it
+    // before would let a concurrent `list` cache the state from before the
+    // change, and a failed command may have changed the images partially.
+    fn changing<T>(&self, command: impl FnOnce(&SerialSpecs) -> T) -> T {
+        let result = match &self.transport {
+            Transport::Serial(specs) => command(specs),
+        };
+        self.invalidate();
+        result
+    }
+
+    pub fn list_hashes(&self) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+        Ok(image_hashes(self.list()?))
+    }
+
+    pub fn running_hash(&self) -> Result<Vec<u8>, Error> {
+        running_image_hash(&self.list()?)
+    }
+
+    pub fn find_slot_by_version(&self, version: &str) -> Result<u32, Error> {
+        slot_of_version(&self.list()?, version)
+    }
+
+    pub fn find_slot_by_hash(&self, hash: &[u8]) -> Result<u32, Error> {
+        slot_of_hash(&self.list()?, hash)
+    }
+
+    pub fn flash_area_info(&self) -> Result<Vec<FlashArea>, Error> {
+        match &self.transport {
+            Transport::Serial(specs) => flash_area_info(specs),
+        }
+    }
+
+    pub fn upload<F>(
+        &self,
+        options: &UploadOptions,
+        filename: &PathBuf,
+        slot: impl Into<Slot>,
+        progress: Option<F>,
+    ) -> Result<UploadStats, Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        self.changing(|specs| upload(specs, options, filename, slot, progress))
+    }
+
+    pub fn upload_events(
+        &self,
+        options: &UploadOptions,
+        filename: &Path,
+        slot: impl Into<Slot>,
+        bound: usize,
+    ) -> (
+        Receiver<UploadProgress>,
+        JoinHandle<Result<UploadStats, Error>>,
+    ) {
+        let cache = Arc::clone(&self.cached_state);
+        let done = move || {
+            lock_cache(&cache).take();
+        };
+        match &self.transport {
+            Transport::Serial(specs) => {
+                upload_events_then(specs, options, filename, slot.into(), bound, done)
+            }
+        }
+    }
+
+    pub fn estimate_upload(
+        &self,
+        options: &UploadOptions,
+        data_len: usize,
+    ) -> Result<UploadEstimate, Error> {
+        match &self.transport {
+            Transport::Serial(specs) => estimate_upload(specs, options, data_len),
+        }
+    }
+
+    pub fn test(&self, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
+        self.changing(|specs| test(specs, hash, confirm))
+    }
+
+    pub fn mark_confirmed(&self, hash: Option<Vec<u8>>) -> Result<ImageStateRsp, Error> {
+        self.changing(|specs| mark_confirmed(specs, hash))
+    }
+
+    pub fn confirm_slot(&self, slot: u8) -> Result<ImageStateRsp, Error> {
+        self.changing(|specs| confirm_slot(specs, slot))
+    }
+
+    pub fn erase(&self, slot: Option<Slot>, image: Option<u32>) -> Result<(), Error> {
+        self.changing(|specs| erase(specs, slot, image))
+    }
+
+    pub fn erase_all(&self, slots: &[u32]) -> Result<SlotResults, Error> {
+        self.changing(|specs| erase_all(specs, slots))
+    }
+
+    pub fn reset(&self) -> Result<(), Error> {
+        self.changing(reset)
+    }
+
+    pub fn safe_reset(&self, force: bool) -> Result<(), Error> {
+        self.changing(|specs| safe_reset(specs, force))
+    }
+
+    pub fn recovery_status(&self) -> Result<bool, Error> {
+        Ok(in_recovery(&self.list()?))
+    }
+
+    pub fn exit_recovery(&self) -> Result<(), Error> {
+        self.changing(exit_recovery)
+    }
+
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        match &self.transport {
+            Transport::Serial(specs) => capabilities(specs),
+        }
+    }
+
+    pub fn detect_context(&self) -> Result<DeviceContext, Error> {
+        match &self.transport {
+            Transport::Serial(specs) => detect_context(specs),
+        }
+    }
+
+    pub fn probe(&self) -> Result<DeviceProbe, Error> {
+        match &self.transport {
+            Transport::Serial(specs) => probe(specs),
+        }
+    }
+}
+
+// a panic while holding the lock leaves a valid cache, so ignore poisoning
+fn lock_cache(cache: &StateCache) -> MutexGuard<'_, Option<(Instant, ImageStateRsp)>> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Upload a file to each device, running up to `concurrency` uploads at a
+/// time. Returns the result of each upload, in the order of `targets`.
+pub fn batch_upload(
+    targets: Vec<(Transport, PathBuf)>,
+    options: &UploadOptions,
+    slot: Slot,
+    concurrency: usize,
+) -> Vec<Result<UploadStats, Error>> {
+    let next = Mutex::new(0);
+    let results: Mutex<Vec<Option<Result<UploadStats, Error>>>> =
+        Mutex::new(targets.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, targets.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = {
+                    let mut next = next.lock().unwrap();
+                    *next += 1;
+                    *next - 1
+                };
+                let Some((transport, filename)) = targets.get(i) else {
+                    break;
+                };
+                let result = match transport {
+                    Transport::Serial(specs) => {
+                        upload(specs, options, filename, slot, None::<fn(u64, u64)>)
+                            .map_err(|e| e.context(format!("upload to {}", specs.device)))
+                    }
+                };
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every target is uploaded"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::tests::TempFile;
+    use crate::test_serial_port::test_specs;
+
+    #[test]
+    fn test_client_list() {
+        let client = Client::serial(test_specs());
+        let state = client.list().unwrap();
+        assert_eq!(state.images.len(), 1);
+        assert!(state.images[0].active);
+        assert!(client.cached().is_none());
+    }
+
+    #[test]
+    fn test_batch_upload() {
+        let file = TempFile::new(&[0x5a; 1000]);
+        let target = |name: &PathBuf| (Transport::Serial(test_specs()), name.clone());
+        // the path of a removed file
+        let missing = TempFile::new(&[]).path().clone();
+        let targets = vec![target(file.path()), target(&missing), target(file.path())];
+        let results = batch_upload(targets, &UploadOptions::default(), Slot::Secondary, 2);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().bytes, 1000);
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap().bytes, 1000);
+    }
+
+    #[test]
+    fn test_client_shared() {
+        fn shared<T: Send + Sync>() {}
+        shared::<Client>();
+    }
+
+    #[test]
+    fn test_client_cache() {
+        let client = Client::serial(test_specs()).with_cache(Duration::from_secs(60));
+        client.list().unwrap();
+        let (read_at, _) = client.cached().unwrap();
+        client.list().unwrap();
+        assert_eq!(client.cached().unwrap().0, read_at);
+
+        // served from the cache
+        let hashes = client.list_hashes().unwrap();
+        assert_eq!(client.running_hash().unwrap(), hashes[0].1);
+        assert!(!client.recovery_status().unwrap());
+        assert_eq!(client.cached().unwrap().0, read_at);
+
+        client.refresh().unwrap();
+        assert!(client.cached().unwrap().0 > read_at);
+
+        client.erase(Some(Slot::Secondary), None).unwrap();
+        assert!(client.cached().is_none());
+
+        // a list while uploading is cached, then dropped when the upload ends
+        let file = TempFile::new(&[0x5a; 2000]);
+        let options = UploadOptions::default();
+        let (events, handle) = client.upload_events(&options, file.path(), Slot::Secondary, 1);
+        client.list().unwrap();
+        assert!(client.cached().is_some());
+        assert!(events.iter().count() > 1);
+        handle.join().unwrap().unwrap();
+        assert!(client.cached().is_none());
+    }
+}
@@ -3,14 +3,24 @@
 use anyhow::{bail, Error, Result};
 use log::debug;
 use log::info;
-use serde_cbor;
-use serde_json;
+use log::warn;
+use serialport::SerialPort;
+use std::collections::BTreeMap;
 
+use crate::image::{list, recovery_status};
 use crate::nmp_hdr::*;
+use crate::protocol::check_answer;
+use crate::protocol::check_rc_version;
+use crate::protocol::encode_body;
+use crate::protocol::encode_frame;
+use crate::protocol::encode_packet;
+use crate::protocol::McuMgrError;
 use crate::transfer::encode_request;
 use crate::transfer::next_seq_id;
 use crate::transfer::open_port;
 use crate::transfer::transceive;
+use crate::transfer::transceive_with_retry;
+use crate::transfer::RetryPolicy;
 use crate::transfer::SerialSpecs;
 
 pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
@@ -19,26 +29,67 @@ pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
     // open serial port
     let mut port = open_port(specs)?;
 
+    reset_on_port(&mut *port, specs)
+}
+
+/// `reset` on a port opened by the caller, e.g. a `StreamPort`
+pub fn reset_on_port(port: &mut dyn SerialPort, specs: &SerialSpecs) -> Result<(), Error> {
+    send_reset(port, specs, next_seq_id())
+}
+
+// why resetting into this state is risky, if it is
+fn reset_warning(state: &ImageStateRsp) -> Option<String> {
+    let pending = state.images.iter().find(|img| img.pending)?;
+    if state.images.iter().any(|img| img.confirmed) {
+        return None;
+    }
+    Some(format!(
+        "image in slot {} is pending, and no confirmed image to revert to",
+        pending.slot
+    ))
+}
+
+/// Reset after checking that a pending image has a confirmed image to fall
+/// back to, unless `force` is set.
+pub fn safe_reset(specs: &SerialSpecs, force: bool) -> Result<(), Error> {
+    if let Some(warning) = reset_warning(&list(specs)?) {
+        if !force {
+            bail!("{}, use force to reset anyway", warning);
+        }
+        warn!("{}", warning);
+    }
+    reset(specs)
+}
+
+// Empty unless a reset delay is set. Firmware without support for it
+// ignores the unknown key.
+fn reset_body(specs: &SerialSpecs) -> Result<Vec<u8>, Error> {
+    match specs.reset_delay_ms {
+        Some(delay) => encode_body(&BTreeMap::from([("delay", delay)]), specs.canonical_cbor),
+        None => Ok(Vec::new()),
+    }
+}
+
+pub(crate) fn send_reset(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    seq_id: u8,
+) -> Result<(), Error> {
     // send request
-    let body = Vec::new();
+    let body = reset_body(specs)?;
     let (data, request_header) = encode_request(
-        specs.linelength,
+        specs,
         NmpOp::Write,
         NmpGroup::Default,
         NmpIdDef::Reset,
         &body,
-        next_seq_id(),
+        seq_id,
     )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
-    
-    // verify sequence id
-    if response_header.seq != request_header.seq {
-        bail!("wrong sequence number");
-    }
+    let (response_header, response_body) = transceive(&mut *port, specs, &data)?;
 
     // verify response
-    if response_header.op != NmpOp::WriteRsp || response_header.group != NmpGroup::Default {
-        bail!("wrong response types");
+    if !check_answer(&request_header, &response_header) {
+        bail!("wrong answer types");
     }
 
     // verify result code
@@ -46,22 +97,265 @@ pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
         "response_body: {}",
         serde_json::to_string_pretty(&response_body)?
     );
-    if let serde_cbor::Value::Map(object) = response_body {
-        for (key, val) in object.iter() {
-            match key {
-                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                    if let serde_cbor::Value::Integer(rc) = val {
-                        if *rc != 0 {
-                            bail!("rc = {}", rc);
-                        } else {
-                            info!("reset complete");
-                        }
-                    }
-                }
-                _ => (),
+    check_rc_version(&response_body, Some(response_header.smp_version()))?;
+    info!("reset complete");
+
+    Ok(())
+}
+
+/// Leave MCUboot serial recovery by resetting into the application
+pub fn exit_recovery(specs: &SerialSpecs) -> Result<(), Error> {
+    if !recovery_status(specs)? {
+        bail!("device not in recovery mode");
+    }
+    reset(specs)
+}
+
+pub fn mcumgr_params(specs: &SerialSpecs) -> Result<McumgrParamsRsp, Error> {
+    info!("send mcumgr parameters request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    // send request
+    let (response_header, response_body) = transceive_with_retry(
+        &mut *port,
+        specs,
+        NmpOp::Read,
+        NmpGroup::Default,
+        NmpIdDef::McumgrParams,
+        &std::collections::BTreeMap::<String, String>::new(),
+        &RetryPolicy::from(specs),
+    )?;
+
+    check_rc_version(&response_body, Some(response_header.smp_version()))?;
+
+    let ans: McumgrParamsRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans)
+}
+
+/// SMP version of the device, 1 or 2. The request is sent as SMP v2, a
+/// device only supporting SMP v1 answers with version 1.
+pub fn smp_version(specs: &SerialSpecs) -> Result<u8, Error> {
+    info!("send SMP version request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    read_smp_version(&mut *port, specs, next_seq_id())
+}
+
+fn read_smp_version(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    seq_id: u8,
+) -> Result<u8, Error> {
+    // the answer tells the version whatever the command, even an error
+    let body = serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new())?;
+    let (mut packet, mut request_header) = encode_packet(
+        NmpOp::Read,
+        NmpGroup::Default,
+        NmpIdDef::McumgrParams,
+        &body,
+        seq_id,
+    )?;
+    request_header.version = 1;
+    packet[..NMP_HDR_LEN].copy_from_slice(&request_header.serialize()?);
+    let data = encode_frame(
+        &packet,
+        specs.linelength,
+        specs.start_marker,
+        specs.continuation_marker,
+    )?;
+    let (response_header, _) = transceive(port, specs, &data)?;
+    if !check_answer(&request_header, &response_header) {
+        bail!("wrong answer types");
+    }
+
+    Ok(response_header.smp_version())
+}
+
+pub fn app_info(specs: &SerialSpecs, format: Option<&str>) -> Result<String, Error> {
+    info!("send application info request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    // send request
+    let req = AppInfoReq {
+        format: format.map(|f| f.to_string()),
+    };
+    let (response_header, response_body) = transceive_with_retry(
+        &mut *port,
+        specs,
+        NmpOp::Read,
+        NmpGroup::Default,
+        NmpIdDef::AppInfo,
+        &req,
+        &RetryPolicy::from(specs),
+    )?;
+
+    check_rc_version(&response_body, Some(response_header.smp_version()))?;
+
+    let ans: AppInfoRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans.output)
+}
+
+// reason from a reset reason response, as text or as a vendor specific number
+fn parse_reset_reason(
+    response_body: &serde_cbor::Value,
+    smp_version: Option<u8>,
+) -> Result<String, Error> {
+    if let Err(e) = check_rc_version(response_body, smp_version) {
+        if let Some(McuMgrError::DeviceError { rc, .. }) = e.downcast_ref() {
+            if *rc == NmpErr::ENotSup as i64 {
+                bail!("reset reason not supported by the device");
             }
         }
+        return Err(e);
     }
+    if let serde_cbor::Value::Map(object) = response_body {
+        match object.get(&serde_cbor::Value::Text("reason".to_string())) {
+            Some(serde_cbor::Value::Text(reason)) => return Ok(reason.clone()),
+            Some(serde_cbor::Value::Integer(reason)) => return Ok(reason.to_string()),
+            _ => (),
+        }
+    }
+    bail!("unexpected answer from device, no reset reason");
+}
 
-    Ok(())
+/// Read why the device last reset. There is no standard command for it, so
+/// the group and id of the vendor command are given by the caller. The
+/// response is expected to hold a `reason`, as text or as a number.
+pub fn reset_reason(specs: &SerialSpecs, group: NmpGroup, id: u8) -> Result<String, Error> {
+    info!("send reset reason request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    // send request
+    let (response_header, response_body) = transceive_with_retry(
+        &mut *port,
+        specs,
+        NmpOp::Read,
+        group,
+        id,
+        &std::collections::BTreeMap::<String, String>::new(),
+        &RetryPolicy::from(specs),
+    )?;
+
+    parse_reset_reason(&response_body, Some(response_header.smp_version()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{encode_frame, encode_packet};
+    use crate::stream_port::StreamPort;
+    use crate::test_serial_port::{test_specs, MockStream};
+    use std::time::Duration;
+
+    fn reset_answered_by(specs: &SerialSpecs, group: NmpGroup) -> Result<(), Error> {
+        let mut rsp = BTreeMap::new();
+        rsp.insert("rc", 0);
+        let body = serde_cbor::to_vec(&rsp).unwrap();
+        let (packet, _) = encode_packet(NmpOp::WriteRsp, group, NmpIdDef::Reset, &body, 7)?;
+        let frame = encode_frame(
+            &packet,
+            specs.linelength,
+            specs.start_marker,
+            specs.continuation_marker,
+        )?;
+        let mut port = StreamPort::new(MockStream::new([frame]), Duration::from_secs(1));
+        send_reset(&mut port, specs, 7)
+    }
+
+    #[test]
+    fn test_reset_warning() {
+        let specs = test_specs();
+        let mut state = list(&specs).unwrap();
+        assert!(reset_warning(&state).is_none());
+
+        let mut pending = state.images[0].clone();
+        pending.slot = 1;
+        pending.active = false;
+        pending.pending = true;
+        state.images.push(pending);
+        assert!(reset_warning(&state).unwrap().contains("slot 1"));
+        state.images[0].confirmed = true;
+        assert!(reset_warning(&state).is_none());
+
+        // nothing pending on the test device
+        assert!(safe_reset(&specs, false).is_ok());
+    }
+
+    #[test]
+    fn test_smp_version() {
+        let specs = SerialSpecs::default();
+        let answered_with = |version: u8| {
+            let body = serde_cbor::to_vec(&BTreeMap::<String, String>::new()).unwrap();
+            let (mut packet, mut header) = encode_packet(
+                NmpOp::ReadRsp,
+                NmpGroup::Default,
+                NmpIdDef::McumgrParams,
+                &body,
+                7,
+            )
+            .unwrap();
+            header.version = version;
+            packet[..NMP_HDR_LEN].copy_from_slice(&header.serialize().unwrap());
+            let frame = encode_frame(
+                &packet,
+                specs.linelength,
+                specs.start_marker,
+                specs.continuation_marker,
+            )
+            .unwrap();
+            let mut port = StreamPort::new(MockStream::new([frame]), Duration::from_secs(1));
+            read_smp_version(&mut port, &specs, 7).unwrap()
+        };
+        assert_eq!(answered_with(0), 1);
+        assert_eq!(answered_with(1), 2);
+    }
+
+    #[test]
+    fn test_parse_reset_reason() {
+        let rsp = |key: &str, value: serde_cbor::Value| {
+            let mut map = BTreeMap::new();
+            map.insert(serde_cbor::Value::Text(key.to_string()), value);
+            serde_cbor::Value::Map(map)
+        };
+        let reason = rsp("reason", serde_cbor::Value::Text("watchdog".to_string()));
+        assert_eq!(parse_reset_reason(&reason, None).unwrap(), "watchdog");
+        let reason = rsp("reason", serde_cbor::Value::Integer(3));
+        assert_eq!(parse_reset_reason(&reason, None).unwrap(), "3");
+
+        let unsupported = rsp("rc", serde_cbor::Value::Integer(8));
+        let e = parse_reset_reason(&unsupported, None).unwrap_err();
+        assert_eq!(e.to_string(), "reset reason not supported by the device");
+        assert!(parse_reset_reason(&rsp("rc", serde_cbor::Value::Integer(1)), None).is_err());
+    }
+
+    #[test]
+    fn test_reset_checks_answer() {
+        let specs = SerialSpecs::default();
+        assert!(reset_answered_by(&specs, NmpGroup::Default).is_ok());
+        let e = reset_answered_by(&specs, NmpGroup::Image).unwrap_err();
+        assert_eq!(e.to_string(), "wrong answer types");
+    }
+
+    #[test]
+    fn test_reset_delay() {
+        let mut specs = test_specs();
+        assert!(reset_body(&specs).unwrap().is_empty());
+        specs.reset_delay_ms = Some(500);
+        let body: BTreeMap<String, u32> =
+            serde_cbor::from_slice(&reset_body(&specs).unwrap()).unwrap();
+        assert_eq!(body, BTreeMap::from([("delay".to_string(), 500)]));
+        reset(&specs).unwrap();
+    }
 }
@@ -3,91 +3,217 @@
 use anyhow::{bail, Error, Result};
 use humantime::format_duration;
 use log::{debug, info, warn};
-use serde_cbor;
-use serde_json;
+use serde::Serialize;
+use serialport::SerialPort;
 use sha2::{Digest, Sha256};
-use std::fs::read;
-use std::path::PathBuf;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{read, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 use std::time::Instant;
 
+use crate::default::send_reset;
+use crate::mcuboot::{mcuboot_image_hash_by, parse_mcuboot_tlvs};
 use crate::nmp_hdr::*;
+use crate::protocol::check_answer;
+use crate::protocol::check_rc_version;
+use crate::protocol::encode_body;
+use crate::protocol::McuMgrError;
 use crate::transfer::encode_request;
+use crate::transfer::is_timeout;
 use crate::transfer::next_seq_id;
 use crate::transfer::open_port;
+use crate::transfer::resync;
 use crate::transfer::transceive;
+use crate::transfer::transceive_with_retry;
+use crate::transfer::RetryPolicy;
 use crate::transfer::SerialSpecs;
+use crate::version::ImageVersion;
 
-fn get_rc(response_body: &serde_cbor::Value) -> Option<u32> {
-    let mut rc: Option<u32> = None;
-    if let serde_cbor::Value::Map(object) = response_body {
-        for (key, val) in object.iter() {
-            match key {
-                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                    if let serde_cbor::Value::Integer(parsed_rc) = val {
-                        rc = Some(*parsed_rc as u32);
-                    }
-                }
-                _ => (),
-            }
+// with adaptive chunks, number of good chunks before probing a larger chunk size
+const ADAPTIVE_GROW_AFTER: u32 = 8;
+
+// with adaptive chunks, smallest chunk size to back off to after a lost chunk
+const ADAPTIVE_MIN_CHUNK: usize = 32;
+
+// with adaptive timeouts, round trips kept for the median, and how many are
+// needed before the timeout is derived from them
+const RTT_WINDOW: usize = 16;
+const RTT_MIN_SAMPLES: usize = 4;
+
+// with adaptive timeouts, the timeout is this many times the median round trip
+const RTT_TIMEOUT_FACTOR: u32 = 3;
+
+// with adaptive timeouts, shortest timeout, below it OS scheduling causes misses
+const RTT_MIN_TIMEOUT: Duration = Duration::from_millis(20);
+
+// time for the device to write a chunk and answer, when estimating an upload
+// without a measured round trip
+const ESTIMATE_ROUND_TRIP: Duration = Duration::from_millis(20);
+
+/// Image slot, by role in the common single image layout or by number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Primary,
+    Secondary,
+    Num(u8),
+}
+
+impl Slot {
+    pub fn number(&self) -> u8 {
+        match self {
+            Slot::Primary => 0,
+            Slot::Secondary => 1,
+            Slot::Num(n) => *n,
         }
     }
-    rc
 }
 
-fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr) -> bool {
-    // verify sequence id
-    if response_header.seq != request_header.seq {
-        log::debug!("wrong sequence number");
-        return false;
+impl From<u8> for Slot {
+    fn from(n: u8) -> Slot {
+        Slot::Num(n)
     }
+}
 
-    let expected_op_type = match request_header.op {
-        NmpOp::Read => NmpOp::ReadRsp,
-        NmpOp::Write => NmpOp::WriteRsp,
-        _ => return false,
-    };
+impl FromStr for Slot {
+    type Err = Error;
 
-    // verify response
-    if response_header.op != expected_op_type || response_header.group != request_header.group {
-        log::debug!("wrong response types");
-        return false;
+    fn from_str(s: &str) -> Result<Slot, Error> {
+        match s.to_lowercase().as_str() {
+            "primary" => Ok(Slot::Primary),
+            "secondary" => Ok(Slot::Secondary),
+            n => n
+                .parse()
+                .map(Slot::Num)
+                .map_err(|_| anyhow::format_err!("invalid slot: {}", s)),
+        }
     }
+}
 
-    true
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Slot::Primary => write!(f, "primary"),
+            Slot::Secondary => write!(f, "secondary"),
+            Slot::Num(n) => write!(f, "{}", n),
+        }
+    }
 }
 
-pub fn erase(specs: &SerialSpecs, slot: Option<u32>) -> Result<(), Error> {
+pub fn erase(specs: &SerialSpecs, slot: Option<Slot>, image: Option<u32>) -> Result<(), Error> {
+    erase_slot(specs, slot.map(|slot| slot.number() as u32), image)
+}
+
+fn erase_slot(specs: &SerialSpecs, slot: Option<u32>, image: Option<u32>) -> Result<(), Error> {
     info!("erase request");
 
     // open serial port
     let mut port = open_port(specs)?;
 
-    let req = ImageEraseReq { slot: slot };
-    let body = serde_cbor::to_vec(&req)?;
+    erase_on_port(&mut *port, specs, slot, image)
+}
+
+fn erase_on_port(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    slot: Option<u32>,
+    image: Option<u32>,
+) -> Result<(), Error> {
     // send request
-    let (data, request_header) = encode_request(
-        specs.linelength,
+    let req = ImageEraseReq { slot, image };
+    let (response_header, response_body) = transceive_with_retry(
+        port,
+        specs,
         NmpOp::Write,
         NmpGroup::Image,
         NmpIdImage::Erase,
-        &body,
-        next_seq_id(),
+        &req,
+        &RetryPolicy::from(specs),
     )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
 
-    if !check_answer(&request_header, &response_header) {
-        bail!("wrong answer types")
-    }
+    check_rc_version(&response_body, Some(response_header.smp_version()))?;
+
+    log::debug!("{:?}", response_body);
+    Ok(())
+}
+
+/// result of an operation per slot
+pub type SlotResults = Vec<(u32, Result<(), Error>)>;
 
-    if let Some(rc) = get_rc(&response_body) {
-        if rc != 0 {
-            bail!("Error from device: {}", rc);
+/// Erase each of the given slots, continuing past failures. Returns the
+/// result per slot, and an error only if every slot failed.
+pub fn erase_all(specs: &SerialSpecs, slots: &[u32]) -> Result<SlotResults, Error> {
+    let results: SlotResults = slots
+        .iter()
+        .map(|slot| (*slot, erase_slot(specs, Some(*slot), None)))
+        .collect();
+
+    for (slot, result) in results.iter() {
+        if let Err(e) = result {
+            warn!("erase of slot {} failed: {}", slot, e);
         }
     }
+    if !results.is_empty() && results.iter().all(|(_, result)| result.is_err()) {
+        bail!("erase failed for all {} slots", results.len());
+    }
 
-    log::debug!("{:?}", response_body);
-    Ok(())
+    Ok(results)
+}
+
+/// Decode an image hash as printed by list, with an optional "0x" prefix
+/// and whitespace anywhere in it
+pub fn parse_hash(hash_hex: &str) -> Result<Vec<u8>, Error> {
+    let trimmed = hash_hex.trim();
+    let digits: String = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed)
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    hex::decode(&digits).map_err(|e| anyhow::format_err!("invalid image hash {}: {}", hash_hex, e))
+}
+
+/// `test` with the hash given as hex
+pub fn test_hex(specs: &SerialSpecs, hash_hex: &str, confirm: Option<bool>) -> Result<(), Error> {
+    test(specs, parse_hash(hash_hex)?, confirm)
+}
+
+// why confirming the image with this hash is suspicious, if it is
+fn confirm_warning(state: &ImageStateRsp, hash: &[u8]) -> Option<String> {
+    match state.images.iter().find(|img| img.hash == hash) {
+        None => Some(format!(
+            "image {} not found on the device",
+            hex::encode(hash)
+        )),
+        Some(img) if !img.pending && !img.active => Some(format!(
+            "image {} in slot {} is neither pending nor running",
+            hex::encode(hash),
+            img.slot
+        )),
+        Some(_) => None,
+    }
+}
+
+// confirming with a stale hash makes the wrong image permanent, so look it up
+// first and return the warning that was logged, if any
+fn warn_before_confirm(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    hash: &[u8],
+) -> Result<Option<String>, Error> {
+    let state = list_on_port(port, specs)?;
+    let warning = confirm_warning(&state, hash);
+    if let Some(warning) = &warning {
+        warn!("{}", warning);
+    }
+    Ok(warning)
 }
 
 pub fn test(specs: &SerialSpecs, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
@@ -96,141 +222,1192 @@ pub fn test(specs: &SerialSpecs, hash: Vec<u8>, confirm: Option<bool>) -> Result
     // open serial port
     let mut port = open_port(specs)?;
 
+    test_on_port(&mut *port, specs, hash, confirm)
+}
+
+/// `test` on a port opened by the caller, e.g. a `StreamPort`
+pub fn test_on_port(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    hash: Vec<u8>,
+    confirm: Option<bool>,
+) -> Result<(), Error> {
+    if confirm == Some(true) && !hash.is_empty() {
+        warn_before_confirm(port, specs, &hash)?;
+    }
+
+    // send request
+    let req = ImageStateReq { hash, confirm };
+    let (response_header, response_body) = transceive_with_retry(
+        port,
+        specs,
+        NmpOp::Write,
+        NmpGroup::Image,
+        NmpIdImage::State,
+        &req,
+        &RetryPolicy::from(specs),
+    )?;
+
+    check_rc_version(&response_body, Some(response_header.smp_version()))?;
+
+    log::debug!("{:?}", response_body);
+    Ok(())
+}
+
+/// Confirm the running image (no hash) or the image with the given hash,
+/// and verify the device reports it as confirmed afterwards.
+pub fn mark_confirmed(specs: &SerialSpecs, hash: Option<Vec<u8>>) -> Result<ImageStateRsp, Error> {
+    info!("confirm image request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    mark_confirmed_on_port(&mut *port, specs, hash)
+}
+
+/// `mark_confirmed` on a port opened by the caller, e.g. a `StreamPort`
+pub fn mark_confirmed_on_port(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    hash: Option<Vec<u8>>,
+) -> Result<ImageStateRsp, Error> {
+    if let Some(hash) = &hash {
+        warn_before_confirm(port, specs, hash)?;
+    }
+
+    // send request
     let req = ImageStateReq {
-        hash: hash,
-        confirm: confirm,
+        hash: hash.clone().unwrap_or_default(),
+        confirm: Some(true),
     };
-    let body = serde_cbor::to_vec(&req)?;
-    // send request
-    let (data, request_header) = encode_request(
-        specs.linelength,
+    let (response_header, response_body) = transceive_with_retry(
+        port,
+        specs,
         NmpOp::Write,
         NmpGroup::Image,
         NmpIdImage::State,
+        &req,
+        &RetryPolicy::from(specs),
+    )?;
+
+    check_rc_version(&response_body, Some(response_header.smp_version()))?;
+
+    let ans: ImageStateRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    // a confirmed image other than the running one is marked permanent until the next boot
+    let confirmed = match &hash {
+        None => ans.images.iter().any(|i| i.active && i.confirmed),
+        Some(hash) => ans
+            .images
+            .iter()
+            .any(|i| i.hash == *hash && (i.confirmed || i.permanent)),
+    };
+    if !confirmed {
+        bail!("image not confirmed by device");
+    }
+
+    Ok(ans)
+}
+
+// hash of the image to confirm in `slot`
+fn slot_hash_to_confirm(state: &ImageStateRsp, slot: u8) -> Result<Vec<u8>, Error> {
+    let images: Vec<&ImageStateEntry> = in_slot(state, slot).collect();
+    match images[..] {
+        [] => bail!("slot {} is empty", slot),
+        [img] if img.hash.is_empty() => bail!("slot {} is empty", slot),
+        [img] if img.active && img.confirmed => {
+            bail!("image in slot {} is already confirmed", slot)
+        }
+        [img] => Ok(img.hash.clone()),
+        _ => bail!("more than one image has a slot {}, confirm by hash", slot),
+    }
+}
+
+// Index of the image and slot in it of an upload slot. The slots are counted
+// across the images, 0 and 1 for the first image, 2 and 3 for the second.
+fn slot_location(slot: u8) -> (usize, u32) {
+    (slot as usize / 2, slot as u32 % 2)
+}
+
+// image number at `index` of the distinct image numbers, in order
+fn nth_image(images: impl Iterator<Item = u32>, index: usize) -> Option<u32> {
+    let mut images: Vec<u32> = images.collect();
+    images.sort();
+    images.dedup();
+    images.get(index).copied()
+}
+
+// entries listed for an upload slot
+fn in_slot(state: &ImageStateRsp, slot: u8) -> impl Iterator<Item = &ImageStateEntry> {
+    let (index, slot) = slot_location(slot);
+    let image = nth_image(state.images.iter().map(|img| img.image), index);
+    state
+        .images
+        .iter()
+        .filter(move |img| img.slot == slot && Some(img.image) == image)
+}
+
+/// Confirm the image in the given slot, looking up its hash first
+pub fn confirm_slot(specs: &SerialSpecs, slot: u8) -> Result<ImageStateRsp, Error> {
+    let hash = slot_hash_to_confirm(&list(specs)?, slot)?;
+    mark_confirmed(specs, Some(hash))
+}
+
+pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
+    info!("send image list request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    list_on_port(&mut *port, specs)
+}
+
+/// `list` on a port opened by the caller, e.g. a `StreamPort`
+pub fn list_on_port(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+) -> Result<ImageStateRsp, Error> {
+    // send request
+    let (_, response_body) = transceive_with_retry(
+        port,
+        specs,
+        NmpOp::Read,
+        NmpGroup::Image,
+        NmpIdImage::State,
+        &std::collections::BTreeMap::<String, String>::new(),
+        &RetryPolicy::from(specs),
+    )?;
+
+    let ans: ImageStateRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+    if ans.has_duplicate_hashes() {
+        warn!("the same image is in more than one slot");
+    }
+
+    Ok(ans)
+}
+
+/// slot of an image, as reported by the slot info command
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FlashArea {
+    pub image: u32,
+    pub slot: u32,
+    pub size: u64,
+    /// image number to upload to this slot, if it can be uploaded to
+    pub upload_image_id: Option<u32>,
+}
+
+/// Size of each slot, where the device supports the slot info command
+pub fn flash_area_info(specs: &SerialSpecs) -> Result<Vec<FlashArea>, Error> {
+    info!("send slot info request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    flash_area_info_on_port(&mut *port, specs)
+}
+
+fn flash_area_info_on_port(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+) -> Result<Vec<FlashArea>, Error> {
+    let (response_header, response_body) = transceive_with_retry(
+        port,
+        specs,
+        NmpOp::Read,
+        NmpGroup::Image,
+        NmpIdImage::SlotInfo,
+        &std::collections::BTreeMap::<String, String>::new(),
+        &RetryPolicy::from(specs),
+    )?;
+    parse_slot_info(response_body, Some(response_header.smp_version()))
+}
+
+fn parse_slot_info(
+    response_body: serde_cbor::Value,
+    smp_version: Option<u8>,
+) -> Result<Vec<FlashArea>, Error> {
+    if let Err(e) = check_rc_version(&response_body, smp_version) {
+        if let Some(McuMgrError::DeviceError { rc, .. }) = e.downcast_ref() {
+            if *rc == NmpErr::ENotSup as i64 {
+                bail!("slot info not supported by the device");
+            }
+        }
+        return Err(e);
+    }
+    let ans: SlotInfoRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans
+        .images
+        .iter()
+        .flat_map(|img| {
+            img.slots.iter().map(|slot| FlashArea {
+                image: img.image,
+                slot: slot.slot,
+                size: slot.size,
+                upload_image_id: slot.upload_image_id,
+            })
+        })
+        .collect())
+}
+
+// the flash area of an upload slot, if the device reports it
+fn slot_area(areas: &[FlashArea], slot: u8) -> Option<&FlashArea> {
+    let (index, slot) = slot_location(slot);
+    let image = nth_image(areas.iter().map(|area| area.image), index)?;
+    areas
+        .iter()
+        .find(|area| area.image == image && area.slot == slot)
+}
+
+// Image number to send with the upload to `slot`, the one the device reports
+// for the slot if it does. Else image 0 for the slots of the first image,
+// which is its secondary slot with or without direct upload support. For
+// the other slots, direct upload takes the slot number, which a device
+// without it refuses, instead of writing to another slot.
+fn upload_image_num(areas: Option<&[FlashArea]>, slot: u8) -> Result<u8, Error> {
+    let Some(area) = areas.and_then(|areas| slot_area(areas, slot)) else {
+        return Ok(if slot < 2 { 0 } else { slot });
+    };
+    match area.upload_image_id {
+        Some(id) => Ok(id as u8),
+        None => bail!("slot {} cannot be uploaded to", slot),
+    }
+}
+
+// fail if the upload slot is too small for the image
+fn check_slot_size(areas: &[FlashArea], slot: u8, len: usize) -> Result<(), Error> {
+    match slot_area(areas, slot) {
+        Some(area) if len as u64 > area.size => bail!(
+            "image is {} bytes, slot {} of image {} holds only {} bytes",
+            len,
+            area.slot,
+            area.image,
+            area.size
+        ),
+        Some(_) => Ok(()),
+        None => {
+            debug!("no slot size reported for slot {}", slot);
+            Ok(())
+        }
+    }
+}
+
+// An application always reports the image it runs from as active. MCUboot
+// serial recovery has no running image and reports only slot, version and
+// hash, so there is no active image.
+pub(crate) fn in_recovery(state: &ImageStateRsp) -> bool {
+    !state.images.iter().any(|img| img.active)
+}
+
+/// Whether the device is in MCUboot serial recovery, where uploads go to the
+/// bootloader instead of the application
+pub fn recovery_status(specs: &SerialSpecs) -> Result<bool, Error> {
+    let recovery = in_recovery(&list(specs)?);
+    debug!("recovery mode: {}", recovery);
+    Ok(recovery)
+}
+
+/// `mark_confirmed` with the hash given as hex
+pub fn confirm_hex(specs: &SerialSpecs, hash_hex: &str) -> Result<ImageStateRsp, Error> {
+    mark_confirmed(specs, Some(parse_hash(hash_hex)?))
+}
+
+/// Slot and hash of each image. Devices answering with only the hashes are
+/// accepted, the other fields of a state entry are optional. SMP has no
+/// request field asking for the hashes only, so this sends a normal state read.
+pub fn list_hashes(specs: &SerialSpecs) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    Ok(image_hashes(list(specs)?))
+}
+
+pub(crate) fn image_hashes(state: ImageStateRsp) -> Vec<(u32, Vec<u8>)> {
+    state
+        .images
+        .into_iter()
+        .map(|img| (img.slot, img.hash))
+        .collect()
+}
+
+// slot of the first image matching the predicate
+fn find_slot<P>(state: &ImageStateRsp, what: &str, predicate: P) -> Result<u32, Error>
+where
+    P: Fn(&ImageStateEntry) -> bool,
+{
+    let slots: Vec<u32> = state
+        .images
+        .iter()
+        .filter(|img| predicate(img))
+        .map(|img| img.slot)
+        .collect();
+    match slots[..] {
+        [] => bail!("no image found with {}", what),
+        [slot] => Ok(slot),
+        _ => bail!(
+            "image with {} found in slots {:?}, select the slot explicitly",
+            what,
+            slots
+        ),
+    }
+}
+
+/// Slot of the image with the given version. Versions that parse compare by
+/// value, so "1.2.3" matches an image reporting "1.2.3.0".
+pub fn find_slot_by_version(specs: &SerialSpecs, version: &str) -> Result<u32, Error> {
+    slot_of_version(&list(specs)?, version)
+}
+
+pub(crate) fn slot_of_version(state: &ImageStateRsp, version: &str) -> Result<u32, Error> {
+    let wanted = version.parse::<ImageVersion>().ok();
+    find_slot(state, &format!("version {}", version), |img| {
+        match (wanted, img.image_version().ok()) {
+            (Some(wanted), Some(actual)) => wanted == actual,
+            _ => img.version == version,
+        }
+    })
+}
+
+/// Slot of the image with the given hash
+pub fn find_slot_by_hash(specs: &SerialSpecs, hash: &[u8]) -> Result<u32, Error> {
+    slot_of_hash(&list(specs)?, hash)
+}
+
+pub(crate) fn slot_of_hash(state: &ImageStateRsp, hash: &[u8]) -> Result<u32, Error> {
+    find_slot(state, &format!("hash {}", hex::encode(hash)), |img| {
+        img.hash == hash
+    })
+}
+
+// hash of the running image, of the first image on multi image devices
+pub(crate) fn running_image_hash(state: &ImageStateRsp) -> Result<Vec<u8>, Error> {
+    match state.images.iter().find(|img| img.active) {
+        Some(img) => Ok(img.hash.clone()),
+        None => bail!("device reports no active image"),
+    }
+}
+
+/// Hash of the running image, to check that a device runs exactly a given
+/// build. Use `hex::encode` to print it.
+pub fn running_hash(specs: &SerialSpecs) -> Result<Vec<u8>, Error> {
+    running_image_hash(&list(specs)?)
+}
+
+/// expected number of chunks and duration of an upload
+#[derive(Debug, Clone, Copy)]
+pub struct UploadEstimate {
+    pub chunks: usize,
+    pub chunk_len: usize,
+    pub duration: Duration,
+}
+
+// length of the encoded upload request for a chunk of chunk_len bytes at off
+fn upload_request_len(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    off: usize,
+    data_len: usize,
+    chunk_len: usize,
+) -> Result<usize, Error> {
+    let req = ImageUploadReq {
+        image_num: 0,
+        off: off as u64,
+        len: (off == 0).then_some(data_len as u64),
+        data_sha: (off == 0 || options.chunk_sha).then(|| vec![0; 32]),
+        upgrade: None,
+        data: vec![0; chunk_len],
+    };
+    let body = encode_body(&req, specs.canonical_cbor)?;
+    let (frame, _) = encode_request(
+        specs,
+        NmpOp::Write,
+        NmpGroup::Image,
+        NmpIdImage::Upload,
         &body,
-        next_seq_id(),
+        0,
     )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+    Ok(frame.len())
+}
+
+// largest chunk at off whose request fits in the MTU, reduced the same way as in upload
+fn fit_chunk_len(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    off: usize,
+    data_len: usize,
+) -> Result<usize, Error> {
+    let mut try_length = specs.mtu;
+    loop {
+        let len = upload_request_len(specs, options, off, data_len, try_length)?;
+        if len <= specs.mtu {
+            return Ok(try_length);
+        }
+        let reduce = len - specs.mtu;
+        if reduce > try_length {
+            return Err(McuMgrError::TooLargeChunk.into());
+        }
+        try_length -= reduce * 3 / 4 + 3;
+    }
+}
+
+/// Estimate the number of chunks for uploading `data_len` bytes with the
+/// MTU of `specs`, and the time it takes to send them at the baudrate, with
+/// a round trip per chunk for the device to write it and answer.
+pub fn estimate_upload(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    data_len: usize,
+) -> Result<UploadEstimate, Error> {
+    let first = fit_chunk_len(specs, options, 0, data_len)?;
+    let chunk_len = fit_chunk_len(specs, options, data_len, data_len)?;
+    let chunks = if data_len <= first {
+        1
+    } else {
+        1 + (data_len - first).div_ceil(chunk_len)
+    };
+
+    // each request fills up to an MTU on the wire, with 10 bits per byte
+    let wire_time = Duration::from_secs_f64((specs.mtu * 10) as f64 / specs.baudrate as f64);
+    let round_trip = options.round_trip.unwrap_or(ESTIMATE_ROUND_TRIP)
+        + Duration::from_millis(specs.response_delay_ms);
+    let duration = (wire_time + round_trip) * chunks as u32;
+
+    Ok(UploadEstimate {
+        chunks,
+        chunk_len,
+        duration,
+    })
+}
+
+/// settings which only apply to uploads
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// maximum number of retransmits for the whole upload
+    pub max_total_retries: Option<u32>,
+    /// MTU when the device is in MCUboot serial recovery
+    pub recovery_mtu: Option<usize>,
+    /// adapt the chunk size to what the link handles
+    pub adaptive_chunk: bool,
+    /// derive the timeout from the measured round trip time
+    pub adaptive_timeout: bool,
+    /// log the progress at this interval
+    pub log_interval: Option<Duration>,
+    /// zero-pad the image to a multiple of this flash write size
+    pub align: Option<usize>,
+    /// send the sha of each chunk instead of the sha of the whole image
+    pub chunk_sha: bool,
+    /// reset the device after a successful upload
+    pub reset_after: bool,
+    /// erase the slot if the upload fails
+    pub cleanup_on_failure: bool,
+    /// refuse images larger than this many bytes
+    pub max_image_size: Option<usize>,
+    /// check that the image fits the slot, if the device reports slot sizes,
+    /// and upload with the image number it reports for the slot
+    pub check_slot_size: bool,
+    /// Read the image while uploading instead of loading it whole. It is read
+    /// with a file read per chunk rather than mapped into memory, which
+    /// needs no more memory and has no undefined behavior if the file is
+    /// changed during the upload.
+    pub stream_image: bool,
+    /// time for the device to write a chunk and answer, for `estimate_upload`
+    pub round_trip: Option<Duration>,
+}
+
+impl UploadOptions {
+    /// check the settings before starting an upload
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(mtu) = self.recovery_mtu {
+            if mtu <= NMP_HDR_LEN {
+                bail!("recovery mtu must be > {}", NMP_HDR_LEN);
+            }
+        }
+        if self.align == Some(0) {
+            bail!("alignment must be > 0");
+        }
+        Ok(())
+    }
+}
+
+/// progress of an upload, after each acknowledged chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UploadProgress {
+    pub offset: u64,
+    pub total: u64,
+    /// retransmits so far
+    pub retries: u32,
+}
+
+/// summary of a finished upload
+#[derive(Debug, Clone, Copy)]
+pub struct UploadStats {
+    pub bytes: usize,
+    pub duration: Duration,
+    /// image data in each acknowledged chunk at the end of the upload, the
+    /// size of the last, shorter chunk only if it was the only one
+    pub chunk_size: usize,
+    pub chunks: u32,
+    pub retransmits: u32,
+}
+
+// size with a binary unit, e.g. "1.2 MiB"
+fn format_size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+impl fmt::Display for UploadStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.duration.as_secs_f64();
+        let rate = if secs > 0.0 {
+            self.bytes as f64 / 1024.0 / secs
+        } else {
+            0.0
+        };
+        write!(
+            f,
+            "{} in {} ({:.0} KiB/s), {} B chunks, {} retransmits",
+            format_size(self.bytes),
+            format_duration(Duration::from_secs(secs.round() as u64)),
+            rate,
+            self.chunk_size,
+            self.retransmits
+        )
+    }
+}
+
+// decode an upload response, failing on an SMP v1 or v2 error or a hash mismatch
+fn parse_upload_rsp(
+    response_header: &NmpHdr,
+    response_body: serde_cbor::Value,
+) -> Result<ImageUploadRsp, Error> {
+    check_rc_version(&response_body, Some(response_header.smp_version()))?;
+    let rsp: ImageUploadRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+    if rsp.data_match == Some(false) {
+        bail!("device reports image hash mismatch");
+    }
+    Ok(rsp)
+}
+
+// show how the image is signed, if it is an MCUboot image
+fn log_image_info(data: &[u8]) {
+    match parse_mcuboot_tlvs(data) {
+        Ok(tlvs) => {
+            for tlv in tlvs.iter() {
+                if tlv.is_signature() {
+                    info!("image signature: {}", tlv.name());
+                } else if tlv.is_hash() || tlv.kind == 0x01 {
+                    info!("image {}: {}", tlv.name(), hex::encode(&tlv.data));
+                }
+            }
+            if !tlvs.iter().any(|tlv| tlv.is_signature()) {
+                warn!("image is not signed");
+            }
+            // uploaded as is, only MCUboot built with encryption support can boot it
+            if let Some(tlv) = tlvs.iter().find(|tlv| tlv.is_encryption_key()) {
+                info!(
+                    "image is encrypted ({}), the device must support encrypted images",
+                    tlv.name()
+                );
+            }
+        }
+        Err(e) => debug!("no MCUboot TLVs: {}", e),
+    }
+}
+
+// Zero-pad the image to a multiple of the flash write size. The upload
+// checksum is sent over the padded data, as that is what the device writes.
+// The image hash reported by list is not affected, MCUboot only hashes the
+// header and the payload, not trailing data after the TLVs.
+fn pad_to_alignment(data: &mut Vec<u8>, align: usize) -> Result<(), Error> {
+    if align == 0 {
+        bail!("alignment must be > 0");
+    }
+    let padded = data.len().div_ceil(align) * align;
+    if padded != data.len() {
+        debug!("padding image from {} to {} bytes", data.len(), padded);
+        data.resize(padded, 0);
+    }
+    Ok(())
+}
+
+// Image data to upload, either in memory or read from the file as needed.
+trait ImageSource {
+    fn size(&self) -> usize;
+
+    fn read_at(&self, off: usize, len: usize) -> Result<Vec<u8>, Error>;
+
+    // sha256 of the whole image, reading it in blocks
+    fn sha256(&self) -> Result<Vec<u8>, Error> {
+        let mut hasher = Sha256::new();
+        let mut off = 0;
+        while off < self.size() {
+            let len = min(HASH_BLOCK, self.size() - off);
+            hasher.update(self.read_at(off, len)?);
+            off += len;
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+}
+
+const HASH_BLOCK: usize = 64 * 1024;
+
+impl ImageSource for Vec<u8> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn read_at(&self, off: usize, len: usize) -> Result<Vec<u8>, Error> {
+        Ok(self[off..off + len].to_vec())
+    }
+}
+
+// Image read from the file chunk by chunk, so a large image is never held
+// in memory. Reads past the end of the file return the alignment padding.
+struct FileImage {
+    file: File,
+    file_len: usize,
+    len: usize,
+}
+
+impl FileImage {
+    fn open(filename: &Path, align: Option<usize>) -> Result<FileImage, Error> {
+        let file = File::open(filename)?;
+        let file_len = file.metadata()?.len() as usize;
+        let len = match align {
+            Some(0) => bail!("alignment must be > 0"),
+            Some(align) => file_len.div_ceil(align) * align,
+            None => file_len,
+        };
+        if len != file_len {
+            debug!("padding image from {} to {} bytes", file_len, len);
+        }
+        Ok(FileImage {
+            file,
+            file_len,
+            len,
+        })
+    }
+}
+
+impl ImageSource for FileImage {
+    fn size(&self) -> usize {
+        self.len
+    }
+
+    fn read_at(&self, off: usize, len: usize) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; len];
+        let in_file = min(off + len, self.file_len).saturating_sub(off);
+        if in_file > 0 {
+            let mut file = &self.file;
+            file.seek(SeekFrom::Start(off as u64))?;
+            file.read_exact(&mut data[..in_file])?;
+        }
+        Ok(data)
+    }
+}
+
+// MTU for an upload, the recovery MTU if set and the device is in serial recovery
+fn upload_mtu(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+) -> Result<usize, Error> {
+    if let Some(recovery_mtu) = options.recovery_mtu {
+        if in_recovery(&list_on_port(port, specs)?) {
+            info!("device in serial recovery, using MTU {}", recovery_mtu);
+            return Ok(recovery_mtu);
+        }
+        info!("device running an application, using MTU {}", specs.mtu);
+    } else {
+        debug!("using MTU {}", specs.mtu);
+    }
+    Ok(specs.mtu)
+}
+
+// a device that restarted during the upload asks for the image from the start
+fn check_offset(sent: usize, received: usize) -> Result<(), Error> {
+    if sent > 0 && received == 0 {
+        bail!("device appears to have reset during upload");
+    }
+    if sent == received {
+        bail!("wrong offset received");
+    }
+    Ok(())
+}
+
+pub fn upload<F>(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    filename: &PathBuf,
+    slot: impl Into<Slot>,
+    mut progress: Option<F>,
+) -> Result<UploadStats, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let progress = |p: UploadProgress| {
+        if let Some(ref mut f) = progress {
+            f(p.offset, p.total);
+        }
+    };
+    upload_with_progress(specs, options, filename, slot.into(), progress)
+}
+
+/// Upload in a thread, sending the progress over a channel holding at most
+/// `bound` events. A consumer not keeping up slows down the upload instead
+/// of queuing events, and the upload goes on if the receiver is dropped.
+pub fn upload_events(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    filename: &Path,
+    slot: impl Into<Slot>,
+    bound: usize,
+) -> (
+    Receiver<UploadProgress>,
+    JoinHandle<Result<UploadStats, Error>>,
+) {
+    upload_events_then(specs, options, filename, slot.into(), bound, || ())
+}
+
+// `upload_events`, calling `done` on the upload thread when it has finished
+pub(crate) fn upload_events_then<D>(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    filename: &Path,
+    slot: Slot,
+    bound: usize,
+    done: D,
+) -> (
+    Receiver<UploadProgress>,
+    JoinHandle<Result<UploadStats, Error>>,
+)
+where
+    D: FnOnce() + Send + 'static,
+{
+    let (tx, rx) = sync_channel(bound);
+    let specs = specs.clone();
+    let options = options.clone();
+    let filename = filename.to_path_buf();
+    let handle = thread::spawn(move || {
+        let result = upload_with_progress(&specs, &options, &filename, slot, |p| {
+            let _ = tx.send(p);
+        });
+        done();
+        result
+    });
+    (rx, handle)
+}
+
+fn upload_with_progress<F>(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    filename: &PathBuf,
+    slot: Slot,
+    progress: F,
+) -> Result<UploadStats, Error>
+where
+    F: FnMut(UploadProgress),
+{
+    options.validate()?;
+
+    let filename_string = filename.to_string_lossy();
+    info!("upload file: {}", filename_string);
+
+    // special feature: if the name contains "slot1" or "slot3", then use this slot
+    let filename_lowercase = filename_string.to_lowercase();
+    let mut slot = slot.number();
+    if filename_lowercase.contains(&"slot1".to_lowercase()) {
+        slot = 1;
+    }
+    if filename_lowercase.contains(&"slot3".to_lowercase()) {
+        slot = 3;
+    }
+    info!("flashing to slot {}", slot);
+
+    // load file, or only open it if the image is read while uploading
+    let data: Box<dyn ImageSource> = if options.stream_image {
+        let image = FileImage::open(filename, options.align)?;
+        check_max_image_size(options, image.file_len)?;
+        debug!("streaming the image, MCUboot TLVs are not shown");
+        Box::new(image)
+    } else {
+        let mut data = read(filename)?;
+        check_max_image_size(options, data.len())?;
+        log_image_info(&data);
+        if let Some(align) = options.align {
+            pad_to_alignment(&mut data, align)?;
+        }
+        Box::new(data)
+    };
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    upload_source_on_port(&mut *port, specs, options, &*data, slot, progress)
+}
+
+/// `upload` of an image in memory, on a port opened by the caller, e.g. a
+/// `StreamPort`
+pub fn upload_on_port<F>(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    image: &[u8],
+    slot: impl Into<Slot>,
+    mut progress: Option<F>,
+) -> Result<UploadStats, Error>
+where
+    F: FnMut(u64, u64),
+{
+    options.validate()?;
+    check_max_image_size(options, image.len())?;
+    let mut data = image.to_vec();
+    if let Some(align) = options.align {
+        pad_to_alignment(&mut data, align)?;
+    }
+    let progress = |p: UploadProgress| {
+        if let Some(ref mut f) = progress {
+            f(p.offset, p.total);
+        }
+    };
+    upload_source_on_port(port, specs, options, &data, slot.into().number(), progress)
+}
+
+fn upload_source_on_port<F>(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    data: &dyn ImageSource,
+    slot: u8,
+    progress: F,
+) -> Result<UploadStats, Error>
+where
+    F: FnMut(UploadProgress),
+{
+    // the bootloader may accept a different MTU than the application
+    let specs = &SerialSpecs {
+        mtu: upload_mtu(port, specs, options)?,
+        ..specs.clone()
+    };
+
+    // before the device erases the slot
+    let areas = if options.check_slot_size {
+        flash_area_info_on_port(port, specs)
+            .map_err(|e| warn!("cannot check the slot size: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    if let Some(areas) = &areas {
+        check_slot_size(areas, slot, data.size())?;
+    }
+    let image_num = upload_image_num(areas.as_deref(), slot)?;
+    debug!("uploading as image {}", image_num);
+
+    let stats = match upload_chunks(port, specs, options, data, image_num, progress) {
+        Ok(stats) => stats,
+        Err(e) if options.cleanup_on_failure => {
+            return Err(cleanup_failed_upload(port, specs, slot, e))
+        }
+        Err(e) => return Err(e),
+    };
+
+    if options.reset_after {
+        info!("send reset request");
+        match send_reset(port, specs, next_seq_id()) {
+            Ok(()) => (),
+            // the device may reboot before it answers
+            Err(e)
+                if is_timeout(&e)
+                    || matches!(
+                        e.downcast_ref(),
+                        Some(McuMgrError::Transport(_) | McuMgrError::DeviceDisconnected(_))
+                    ) =>
+            {
+                info!("no answer to the reset request, device is rebooting");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(stats)
+}
+
+fn check_max_image_size(options: &UploadOptions, len: usize) -> Result<(), Error> {
+    if let Some(max_size) = options.max_image_size {
+        if len > max_size {
+            bail!(
+                "image is {} bytes, more than the maximum of {} bytes",
+                len,
+                max_size
+            );
+        }
+    }
+    Ok(())
+}
+
+/// result of `flash_verify`
+#[derive(Debug, Clone, Copy)]
+pub struct FlashVerifyStats {
+    /// the upload which was verified
+    pub upload: UploadStats,
+    /// number of times the whole image was uploaded again
+    pub retries: u32,
+}
+
+/// Upload an image and check that the device lists its hash. If the upload
+/// fails or the hash is not listed, the slot is erased and the whole image
+/// is uploaded again, up to `attempts` uploads in total.
+pub fn flash_verify(
+    specs: &SerialSpecs,
+    options: &UploadOptions,
+    filename: &PathBuf,
+    slot: impl Into<Slot>,
+    attempts: u32,
+) -> Result<FlashVerifyStats, Error> {
+    let slot = slot.into();
+    let image = FileImage::open(filename, None)?;
+    let hash = mcuboot_image_hash_by(image.size(), |off, len| image.read_at(off, len))?;
+    let mut last_error = anyhow::format_err!("no upload attempted");
+    for attempt in 0..attempts {
+        if attempt > 0 {
+            warn!("attempt {} of {} failed: {}", attempt, attempts, last_error);
+            if let Err(e) = erase(specs, Some(slot), None) {
+                warn!("erasing slot {} failed: {}", slot, e);
+            }
+        }
+        let upload = match upload(specs, options, filename, slot, None::<fn(u64, u64)>) {
+            Ok(upload) => upload,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        match list(specs) {
+            Ok(state) if in_slot(&state, slot.number()).any(|img| img.hash == hash) => {
+                info!("image {} verified", hex::encode(&hash));
+                return Ok(FlashVerifyStats {
+                    upload,
+                    retries: attempt,
+                });
+            }
+            Ok(_) => {
+                last_error = anyhow::format_err!(
+                    "image {} not listed in slot {}",
+                    hex::encode(&hash),
+                    slot.number()
+                )
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error.context(format!("image not verified after {} attempts", attempts)))
+}
+
+// Erase the slot holding a partial image, so the next attempt starts clean.
+// The upload error is kept, with the outcome of the erase as context.
+fn cleanup_failed_upload(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    slot: u8,
+    e: Error,
+) -> Error {
+    warn!("upload failed, erasing slot {}", slot);
+    let erased = port
+        .set_timeout(Duration::from_secs(specs.initial_timeout_s as u64))
+        .map_err(Error::from)
+        .and_then(|_| erase_on_port(port, specs, Some(slot.into()), None));
+    match erased {
+        Ok(()) => e.context(format!("upload failed, slot {} was erased", slot)),
+        Err(erase_err) => e.context(format!(
+            "upload failed, erasing slot {} also failed: {}",
+            slot, erase_err
+        )),
+    }
+}
+
+// Round trip times of the last chunks, for a timeout following the link
+// speed instead of the fixed subsequent timeout.
+struct RoundTrips {
+    samples: VecDeque<Duration>,
+}
 
-    if !check_answer(&request_header, &response_header) {
-        bail!("wrong answer types")
+impl RoundTrips {
+    fn new() -> RoundTrips {
+        RoundTrips {
+            samples: VecDeque::with_capacity(RTT_WINDOW),
+        }
     }
 
-    if let Some(rc) = get_rc(&response_body) {
-        if rc != 0 {
-            return Err(anyhow::format_err!("Error from device: {}", rc));
+    fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == RTT_WINDOW {
+            self.samples.pop_front();
         }
+        self.samples.push_back(rtt);
     }
 
-    log::debug!("{:?}", response_body);
-    Ok(())
+    // a multiple of the median round trip, `fallback` until enough are measured
+    fn timeout(&self, fallback: Duration) -> Duration {
+        if self.samples.len() < RTT_MIN_SAMPLES {
+            return fallback;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        let median = sorted[sorted.len() / 2];
+        max(median * RTT_TIMEOUT_FACTOR, RTT_MIN_TIMEOUT)
+    }
 }
 
-pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
-    info!("send image list request");
-
-    // open serial port
-    let mut port = open_port(specs)?;
-
-    // send request
-    let body: Vec<u8> =
-        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
-    let (data, request_header) = encode_request(
-        specs.linelength,
-        NmpOp::Read,
-        NmpGroup::Image,
-        NmpIdImage::State,
-        &body,
-        next_seq_id(),
-    )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+// Running hash of the data the device acknowledged, taken from the chunks
+// as they were sent, to check at the end that its offsets covered the image
+// exactly. An acknowledged offset past the data sent is a gap, which fails
+// the check.
+struct AckedHash {
+    hasher: Sha256,
+    len: usize,
+    gap: Option<usize>,
+}
 
-    if !check_answer(&request_header, &response_header) {
-        bail!("wrong answer types")
+impl AckedHash {
+    fn new() -> AckedHash {
+        AckedHash {
+            hasher: Sha256::new(),
+            len: 0,
+            gap: None,
+        }
     }
 
-    let ans: ImageStateRsp = serde_cbor::value::from_value(response_body)
-        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+    // the device acknowledged `chunk`, sent at `start`, up to offset `off`
+    fn ack(
+        &mut self,
+        data: &dyn ImageSource,
+        start: usize,
+        chunk: &[u8],
+        off: usize,
+    ) -> Result<(), Error> {
+        if off < self.len {
+            // The device asks for data again, hash from the start. Everything
+            // before `off` was acknowledged already, so it was sent and is
+            // the same as the image.
+            debug!("device went back from offset {} to {}", self.len, off);
+            self.hasher = Sha256::new();
+            self.hasher.update(data.read_at(0, off)?);
+            self.len = off;
+            return Ok(());
+        }
+        let end = start + chunk.len();
+        if self.len < start || off > end {
+            let gap = if self.len < start { self.len } else { end };
+            warn!(
+                "device acknowledged offset {} but was sent data from {} to {}",
+                off, start, end
+            );
+            self.gap.get_or_insert(gap);
+        }
+        let (from, to) = (max(self.len, start), min(off, end));
+        if from < to {
+            self.hasher.update(&chunk[from - start..to - start]);
+        }
+        self.len = off;
+        Ok(())
+    }
 
-    Ok(ans)
+    fn verify(self, image_sha: &[u8]) -> Result<(), Error> {
+        if let Some(gap) = self.gap {
+            bail!(
+                "device acknowledged data it was not sent, diverging at offset {}",
+                gap
+            );
+        }
+        if self.hasher.finalize().as_slice() != image_sha {
+            bail!(
+                "acknowledged data differs from the image, diverging before offset {}",
+                self.len
+            );
+        }
+        Ok(())
+    }
 }
 
-pub fn upload<F>(
+fn upload_chunks<F>(
+    port: &mut dyn SerialPort,
     specs: &SerialSpecs,
-    filename: &PathBuf,
-    slot: u8,
-    mut progress: Option<F>,
-) -> Result<(), Error>
+    options: &UploadOptions,
+    data: &dyn ImageSource,
+    image_num: u8,
+    mut progress: F,
+) -> Result<UploadStats, Error>
 where
-    F: FnMut(u64, u64),
+    F: FnMut(UploadProgress),
 {
-    let filename_string = filename.to_string_lossy();
-    info!("upload file: {}", filename_string);
-
-    // special feature: if the name contains "slot1" or "slot3", then use this slot
-    let filename_lowercase = filename_string.to_lowercase();
-    let mut slot = slot;
-    if filename_lowercase.contains(&"slot1".to_lowercase()) {
-        slot = 1;
-    }
-    if filename_lowercase.contains(&"slot3".to_lowercase()) {
-        slot = 3;
-    }
-    info!("flashing to slot {}", slot);
-
-    // open serial port
-    let mut port = open_port(specs)?;
-
-    // load file
-    let data = read(filename)?;
-    info!("{} bytes to transfer", data.len());
+    info!("{} bytes to transfer", data.size());
+    let estimate = estimate_upload(specs, options, data.size())?;
+    debug!(
+        "estimated {} chunks of {} bytes, at least {}",
+        estimate.chunks,
+        estimate.chunk_len,
+        format_duration(estimate.duration)
+    );
 
     // transfer in blocks
+    let image_sha = data.sha256()?;
+    let mut acked = AckedHash::new();
+    let mut round_trips = RoundTrips::new();
     let mut off: usize = 0;
     let start_time = Instant::now();
     let mut sent_blocks: u32 = 0;
     let mut confirmed_blocks: u32 = 0;
+    let mut chunk_size = specs.mtu;
+    let mut acked_len: usize = 0;
+    let mut good_chunks: u32 = 0;
+    let mut resynced = false;
+    let mut total_retries: u32 = 0;
+    let mut last_log = start_time;
+    // the shared policy decides when a chunk is sent again, but the chunk is
+    // re-encoded for each retry, as it may shrink, so not `transceive_with_retry`
+    let policy = RetryPolicy::from(specs);
     loop {
-        let mut nb_retry = specs.nb_retry;
+        let mut retries = 0;
         let off_start = off;
-        let mut try_length = specs.mtu;
+        let mut try_length = chunk_size;
         debug!("try_length: {}", try_length);
         let seq_id = next_seq_id();
         loop {
-            // get slot
-            let image_num = slot;
-
             // create image upload request
-            if off + try_length > data.len() {
-                try_length = data.len() - off;
+            if off + try_length > data.size() {
+                try_length = data.size() - off;
             }
-            let chunk = data[off..off + try_length].to_vec();
-            let len = data.len() as u32;
-            let req = if off == 0 {
-                ImageUploadReq {
-                    image_num,
-                    off: off as u32,
-                    len: Some(len),
-                    data_sha: Some(Sha256::digest(&data).to_vec()),
-                    upgrade: None,
-                    data: chunk,
-                }
+            let chunk = data.read_at(off, try_length)?;
+            let len = data.size() as u64;
+            // some servers expect the sha of each chunk instead of the whole image
+            let data_sha = if options.chunk_sha {
+                Some(Sha256::digest(&chunk).to_vec())
+            } else if off == 0 {
+                Some(image_sha.clone())
             } else {
-                ImageUploadReq {
-                    image_num,
-                    off: off as u32,
-                    len: None,
-                    data_sha: None,
-                    upgrade: None,
-                    data: chunk,
-                }
+                None
+            };
+            let req = ImageUploadReq {
+                image_num,
+                off: off as u64,
+                len: if off == 0 { Some(len) } else { None },
+                data_sha,
+                upgrade: None,
+                data: chunk,
             };
             debug!("req: {:?}", req);
 
             // convert to bytes with CBOR
-            let body = serde_cbor::to_vec(&req)?;
+            let body = encode_body(&req, specs.canonical_cbor)?;
             let (chunk, request_header) = encode_request(
-                specs.linelength,
+                specs,
                 NmpOp::Write,
                 NmpGroup::Image,
                 NmpIdImage::Upload,
@@ -242,7 +1419,7 @@ where
             if chunk.len() > specs.mtu {
                 let reduce = chunk.len() - specs.mtu;
                 if reduce > try_length {
-                    bail!("MTU too small");
+                    return Err(McuMgrError::TooLargeChunk.into());
                 }
 
                 // number of bytes to reduce is base64 encoded, calculate back the number of bytes
@@ -254,16 +1431,43 @@ where
 
             // send request
             sent_blocks += 1;
-            let (response_header, response_body) = match transceive(&mut *port, &chunk) {
-                Ok(ret) => ret,
-                Err(e) if e.to_string() == "Operation timed out" => {
-                    if nb_retry == 0 {
-                        return Err(e);
+            let sent_at = Instant::now();
+            let (response_header, response_body) = match transceive(port, specs, &chunk) {
+                // the first chunk waits for the slot erase, it is not a round trip
+                Ok(ret) if off == 0 => ret,
+                Ok(ret) => {
+                    round_trips.record(sent_at.elapsed());
+                    ret
+                }
+                Err(e) if policy.should_retry(retries, &e) => {
+                    retries += 1;
+                    total_retries += 1;
+                    debug!("{}, retry {} of {}", e, retries, policy.max_retries);
+                    if let Some(max) = options.max_total_retries {
+                        if total_retries > max {
+                            bail!("exceeded retry budget after {} retransmits", max);
+                        }
                     }
-                    nb_retry -= 1;
-                    debug!("missed answer, nb_retry: {}", nb_retry);
+                    if !resynced {
+                        // the device may be stuck in a partial frame
+                        resync(port)?;
+                        resynced = true;
+                    }
+                    if options.adaptive_chunk {
+                        // back off to a smaller chunk for the retry
+                        try_length = max(try_length / 2, ADAPTIVE_MIN_CHUNK);
+                        good_chunks = 0;
+                        debug!("new try_length: {}", try_length);
+                    }
+                    thread::sleep(policy.backoff);
                     continue;
                 }
+                Err(e) if off > 0 && is_timeout(&e) => {
+                    return Err(e.context(format!(
+                        "no answer at offset {}, the device may have reset",
+                        off
+                    )));
+                }
                 Err(e) => return Err(e),
             };
 
@@ -276,52 +1480,79 @@ where
                 "response_body: {}",
                 serde_json::to_string_pretty(&response_body)?
             );
-            if let serde_cbor::Value::Map(object) = response_body {
-                for (key, val) in object.iter() {
-                    match key {
-                        serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                            if let serde_cbor::Value::Integer(rc) = val {
-                                if *rc != 0 {
-                                    bail!("rc = {}", rc);
-                                }
-                            }
-                        }
-                        serde_cbor::Value::Text(off_key) if off_key == "off" => {
-                            if let serde_cbor::Value::Integer(off_val) = val {
-                                off = *off_val as usize;
-                            }
-                        }
-                        _ => (),
-                    }
-                }
+            let rsp = parse_upload_rsp(&response_header, response_body)?;
+            if let Some(off_val) = rsp.off {
+                off = off_val as usize;
+            }
+            acked.ack(data, off_start, &req.data, off)?;
+            if acked_len == 0 || off < data.size() {
+                acked_len = req.data.len();
             }
             confirmed_blocks += 1;
             break;
         }
 
         // next chunk, next off should have been sent from the device
-        if off_start == off {
-            bail!("wrong offset received");
-        }
+        check_offset(off_start, off)?;
 
-        if let Some(ref mut f) = progress {
-            f(off as u64, data.len() as u64);
+        progress(UploadProgress {
+            offset: off as u64,
+            total: data.size() as u64,
+            retries: total_retries,
+        });
+
+        if let Some(interval) = options.log_interval {
+            if last_log.elapsed() >= interval {
+                last_log = Instant::now();
+                let rate = off as f64 / 1024.0 / start_time.elapsed().as_secs_f64();
+                info!(
+                    "{}% ({}/{} bytes, {:.1} KiB/s)",
+                    100 * off / data.size(),
+                    off,
+                    data.size(),
+                    rate
+                );
+            }
         }
 
-        //info!("{}% uploaded", 100 * off / data.len());
-        if off == data.len() {
+        if off == data.size() {
             break;
         }
 
+        // keep the size that worked, and after a run of good chunks probe a larger one
+        if options.adaptive_chunk {
+            chunk_size = try_length;
+            good_chunks += 1;
+            if good_chunks >= ADAPTIVE_GROW_AFTER && chunk_size < specs.mtu {
+                chunk_size = min(chunk_size + chunk_size / 4 + 1, specs.mtu);
+                good_chunks = 0;
+                debug!("probing chunk size: {}", chunk_size);
+            }
+        }
+
         // The first packet was sent and the device has cleared its internal flash
         // We can now lower the timeout in case of failed transmission
-        port.set_timeout(Duration::from_millis(specs.subsequent_timeout_ms as u64))?;
+        let mut timeout = Duration::from_millis(specs.subsequent_timeout_ms as u64);
+        if options.adaptive_timeout {
+            timeout = round_trips.timeout(timeout);
+            debug!("timeout: {:?}", timeout);
+        }
+        port.set_timeout(timeout)?;
     }
 
-    let elapsed = start_time.elapsed().as_secs_f64().round();
-    let elapsed_duration = Duration::from_secs(elapsed as u64);
-    let formatted_duration = format_duration(elapsed_duration);
-    info!("upload took {}", formatted_duration);
+    acked.verify(&image_sha)?;
+
+    let stats = UploadStats {
+        bytes: data.size(),
+        duration: start_time.elapsed(),
+        chunk_size: acked_len,
+        chunks: confirmed_blocks,
+        retransmits: sent_blocks - confirmed_blocks,
+    };
+    info!("uploaded {}", stats);
+    if options.adaptive_chunk {
+        info!("converged chunk size: {} bytes", chunk_size);
+    }
     if confirmed_blocks != sent_blocks {
         warn!(
             "upload packet loss {}%",
@@ -329,5 +1560,819 @@ where
         );
     }
 
-    Ok(())
+    Ok(stats)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::{decode_serial_frame, encode_frame, encode_packet};
+    use crate::stream_port::StreamPort;
+    use crate::test_serial_port::test_specs;
+    use base64::{engine::general_purpose, Engine as _};
+    use serde_cbor::Value;
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Uniquely named file in the temp directory, removed when dropped.
+    pub(crate) struct TempFile(PathBuf);
+
+    impl TempFile {
+        pub(crate) fn new(contents: &[u8]) -> TempFile {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "mcumgr-client-test-{}-{}.bin",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+            std::fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        pub(crate) fn path(&self) -> &PathBuf {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_slot() {
+        assert_eq!("primary".parse::<Slot>().unwrap().number(), 0);
+        assert_eq!("Secondary".parse::<Slot>().unwrap().number(), 1);
+        assert_eq!("3".parse::<Slot>().unwrap(), Slot::Num(3));
+        assert!("third".parse::<Slot>().is_err());
+        assert_eq!(Slot::from(2).to_string(), "2");
+        assert!(erase(&test_specs(), Some(Slot::Secondary), None).is_ok());
+    }
+
+    #[test]
+    fn test_erase_all() {
+        let results = erase_all(&test_specs(), &[1, 3]).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+    }
+
+    #[test]
+    fn test_recovery_status() {
+        assert!(!recovery_status(&test_specs()).unwrap());
+
+        let mut state = list(&test_specs()).unwrap();
+        state.images[0].active = false;
+        assert!(in_recovery(&state));
+        state.images.clear();
+        assert!(in_recovery(&state));
+    }
+
+    #[test]
+    fn test_upload_mtu() {
+        let specs = test_specs();
+        let mut port = open_port(&specs).unwrap();
+        let options = UploadOptions::default();
+        assert_eq!(upload_mtu(&mut *port, &specs, &options).unwrap(), specs.mtu);
+
+        // the test device runs an application, listed on the same port
+        let options = UploadOptions {
+            recovery_mtu: Some(128),
+            ..Default::default()
+        };
+        assert_eq!(upload_mtu(&mut *port, &specs, &options).unwrap(), specs.mtu);
+    }
+
+    #[test]
+    fn test_upload_options() {
+        assert!(UploadOptions::default().validate().is_ok());
+        let invalid = [
+            UploadOptions {
+                recovery_mtu: Some(8),
+                ..Default::default()
+            },
+            UploadOptions {
+                align: Some(0),
+                ..Default::default()
+            },
+        ];
+        for options in invalid.iter() {
+            assert!(options.validate().is_err());
+            let filename = PathBuf::from("unused.bin");
+            assert!(upload(&test_specs(), options, &filename, 1, None::<fn(u64, u64)>).is_err());
+        }
+    }
+
+    #[test]
+    fn test_confirm_warning() {
+        let mut state = list(&test_specs()).unwrap();
+        let hash = state.images[0].hash.clone();
+        assert!(confirm_warning(&state, &hash).is_none());
+        assert!(confirm_warning(&state, &[0; 32])
+            .unwrap()
+            .contains("not found"));
+
+        state.images[0].active = false;
+        assert!(confirm_warning(&state, &hash).is_some());
+        state.images[0].pending = true;
+        assert!(confirm_warning(&state, &hash).is_none());
+
+        // only a warning, the request is still sent
+        assert!(test(&test_specs(), vec![0; 32], Some(true)).is_ok());
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        assert_eq!(parse_hash("0a0B").unwrap(), vec![0x0a, 0x0b]);
+        assert_eq!(parse_hash(" 0x0a 0b\n").unwrap(), vec![0x0a, 0x0b]);
+        let e = parse_hash("0x0g").unwrap_err();
+        assert!(e.to_string().starts_with("invalid image hash 0x0g"));
+        assert!(parse_hash("abc").is_err());
+    }
+
+    #[test]
+    fn test_hash_only_state() {
+        let mut entry = BTreeMap::new();
+        entry.insert(Value::Text("slot".to_string()), Value::Integer(1));
+        entry.insert(
+            Value::Text("hash".to_string()),
+            Value::Bytes(vec![0xab; 32]),
+        );
+        let mut rsp = BTreeMap::new();
+        rsp.insert(
+            Value::Text("images".to_string()),
+            Value::Array(vec![Value::Map(entry)]),
+        );
+        let state: ImageStateRsp = serde_cbor::value::from_value(Value::Map(rsp)).unwrap();
+        assert_eq!(state.images[0].slot, 1);
+        assert_eq!(state.images[0].hash, vec![0xab; 32]);
+        assert!(state.images[0].version.is_empty());
+
+        let hashes = list_hashes(&test_specs()).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(hashes[0].0, 0);
+    }
+
+    #[test]
+    fn test_find_slot() {
+        let specs = test_specs();
+        let image = list(&specs).unwrap().images.remove(0);
+        assert_eq!(find_slot_by_hash(&specs, &image.hash).unwrap(), 0);
+        assert!(find_slot_by_hash(&specs, &[0; 32]).is_err());
+
+        assert_eq!(find_slot_by_version(&specs, &image.version).unwrap(), 0);
+        assert_eq!(find_slot_by_version(&specs, "1.0.0.0").unwrap(), 0);
+        let e = find_slot_by_version(&specs, "99.0.0").unwrap_err();
+        assert_eq!(e.to_string(), "no image found with version 99.0.0");
+    }
+
+    #[test]
+    fn test_running_hash() {
+        let specs = test_specs();
+        let mut state = list(&specs).unwrap();
+        assert_eq!(running_hash(&specs).unwrap(), state.images[0].hash);
+
+        state.images[0].active = false;
+        let e = running_image_hash(&state).unwrap_err();
+        assert_eq!(e.to_string(), "device reports no active image");
+    }
+
+    #[test]
+    fn test_estimate_upload() {
+        let specs = test_specs();
+        let options = UploadOptions::default();
+        let estimate = estimate_upload(&specs, &options, 20000).unwrap();
+        assert!(estimate.chunk_len > 0 && estimate.chunk_len < specs.mtu);
+        assert!(estimate.chunks * estimate.chunk_len >= 20000);
+        let len = upload_request_len(&specs, &options, 20000, 20000, estimate.chunk_len);
+        assert!(len.unwrap() <= specs.mtu);
+
+        assert_eq!(estimate_upload(&specs, &options, 10).unwrap().chunks, 1);
+
+        // the sha in every request leaves less room for data
+        let options = UploadOptions {
+            chunk_sha: true,
+            ..Default::default()
+        };
+        let with_sha = estimate_upload(&specs, &options, 20000).unwrap();
+        assert!(with_sha.chunk_len < estimate.chunk_len);
+
+        // 512 bytes at 115200 baud take 44.4 ms, then the round trip
+        let wire_time = Duration::from_secs_f64(5120.0 / 115_200.0);
+        let per_chunk = wire_time + ESTIMATE_ROUND_TRIP;
+        assert_eq!(estimate.duration, per_chunk * estimate.chunks as u32);
+        let options = UploadOptions {
+            round_trip: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let estimate = estimate_upload(&specs, &options, 10).unwrap();
+        assert_eq!(estimate.duration, wire_time + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_upload_len_above_4gib() {
+        let specs = test_specs();
+        let mut port = open_port(&specs).unwrap();
+        let total: u64 = 5 << 30;
+        let mut upload_chunk = |off: u64| {
+            let req = ImageUploadReq {
+                image_num: 0,
+                off,
+                len: (off == 0).then_some(total),
+                data_sha: None,
+                upgrade: None,
+                data: vec![0; 64],
+            };
+            let (header, body) = transceive_with_retry(
+                &mut *port,
+                &specs,
+                NmpOp::Write,
+                NmpGroup::Image,
+                NmpIdImage::Upload,
+                &req,
+                &RetryPolicy::from(&specs),
+            )
+            .unwrap();
+            parse_upload_rsp(&header, body).unwrap().off
+        };
+        assert_eq!(upload_chunk(0), Some(64));
+        let off = u32::MAX as u64 + 1;
+        assert_eq!(upload_chunk(off), Some(off + 64));
+    }
+
+    #[test]
+    fn test_upload_reset_after() {
+        let file = TempFile::new(&[0x5a; 1000]);
+        let options = UploadOptions {
+            reset_after: true,
+            ..Default::default()
+        };
+        let result = upload(
+            &test_specs(),
+            &options,
+            file.path(),
+            Slot::Secondary,
+            None::<fn(u64, u64)>,
+        );
+        let stats = result.unwrap();
+        assert_eq!(stats.bytes, 1000);
+        assert_eq!(stats.retransmits, 0);
+    }
+
+    #[test]
+    fn test_cleanup_failed_upload() {
+        let specs = test_specs();
+        let mut port = open_port(&specs).unwrap();
+        let e = cleanup_failed_upload(&mut *port, &specs, 1, McuMgrError::Timeout.into());
+        assert_eq!(e.to_string(), "upload failed, slot 1 was erased");
+        assert!(matches!(e.downcast_ref(), Some(McuMgrError::Timeout)));
+    }
+
+    #[test]
+    fn test_upload_events() {
+        let file = TempFile::new(&[0x5a; 2000]);
+        let options = UploadOptions::default();
+        let (events, handle) =
+            upload_events(&test_specs(), &options, file.path(), Slot::Secondary, 1);
+        let events: Vec<UploadProgress> = events.iter().collect();
+        let result = handle.join().unwrap();
+        assert_eq!(events.len() as u32, result.unwrap().chunks);
+        let last = events.last().unwrap();
+        assert_eq!((last.offset, last.total, last.retries), (2000, 2000, 0));
+    }
+
+    #[test]
+    fn test_max_image_size() {
+        let file = TempFile::new(&[0x5a; 1000]);
+        let upload_max = |max_image_size| {
+            let options = UploadOptions {
+                max_image_size: Some(max_image_size),
+                ..Default::default()
+            };
+            upload(
+                &test_specs(),
+                &options,
+                file.path(),
+                Slot::Secondary,
+                None::<fn(u64, u64)>,
+            )
+        };
+        let too_large = upload_max(999);
+        let fits = upload_max(1000);
+        assert_eq!(
+            too_large.unwrap_err().to_string(),
+            "image is 1000 bytes, more than the maximum of 999 bytes"
+        );
+        assert!(fits.is_ok());
+    }
+
+    #[test]
+    fn test_flash_area_info() {
+        let areas = flash_area_info(&test_specs()).unwrap();
+        assert_eq!(areas.len(), 2);
+        assert_eq!(areas[1].upload_image_id, Some(1));
+
+        assert!(check_slot_size(&areas, 1, 0x10000).is_ok());
+        let e = check_slot_size(&areas, 1, 0x10001).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "image is 65537 bytes, slot 1 of image 0 holds only 65536 bytes"
+        );
+        // no known slot, nothing to check
+        assert!(check_slot_size(&areas, 3, 0x10001).is_ok());
+
+        let mut rsp = BTreeMap::new();
+        rsp.insert(Value::Text("rc".to_string()), Value::Integer(8));
+        let e = parse_slot_info(Value::Map(rsp), None).unwrap_err();
+        assert_eq!(e.to_string(), "slot info not supported by the device");
+    }
+
+    #[test]
+    fn test_flash_verify() {
+        let flash = |hash: &[u8], slot, attempts| {
+            let file = TempFile::new(&crate::mcuboot::tests::test_image(&[], &[(0x10, hash)]));
+            let options = UploadOptions::default();
+            flash_verify(&test_specs(), &options, file.path(), slot, attempts)
+        };
+
+        // the test device always lists the same image, in slot 0
+        let listed = list(&test_specs()).unwrap().images[0].hash.clone();
+        let stats = flash(&listed, Slot::Primary, 2).unwrap();
+        assert_eq!(stats.retries, 0);
+
+        let e = flash(&[0xaa; 32], Slot::Primary, 2).unwrap_err();
+        assert_eq!(e.to_string(), "image not verified after 2 attempts");
+        assert!(format!("{:#}", e).contains("not listed in slot 0"));
+
+        // listed, but in another slot
+        let e = flash(&listed, Slot::Secondary, 1).unwrap_err();
+        assert!(format!("{:#}", e).contains("not listed in slot 1"));
+    }
+
+    #[test]
+    fn test_in_slot() {
+        let mut state = list(&test_specs()).unwrap();
+        assert_eq!(in_slot(&state, 0).count(), 1);
+        assert_eq!(in_slot(&state, 1).count(), 0);
+
+        // second image, slots 2 and 3
+        let mut second = state.images[0].clone();
+        second.image += 1;
+        second.slot = 1;
+        state.images.push(second);
+        assert_eq!(in_slot(&state, 1).count(), 0);
+        let image = state.images[1].image;
+        assert_eq!(in_slot(&state, 3).next().unwrap().image, image);
+    }
+
+    // two images with an image in each slot, slot n holding hash [n; 32]
+    fn two_images() -> (ImageStateRsp, Vec<FlashArea>) {
+        let mut state = list(&test_specs()).unwrap();
+        let listed = state.images[0].clone();
+        state.images = (0..4)
+            .map(|n| ImageStateEntry {
+                image: n / 2,
+                slot: n % 2,
+                hash: vec![n as u8; 32],
+                active: n % 2 == 0,
+                ..listed.clone()
+            })
+            .collect();
+        let areas = (0..4)
+            .map(|n| FlashArea {
+                image: n / 2,
+                slot: n % 2,
+                size: 0x10000,
+                upload_image_id: (n % 2 == 1).then_some(n),
+            })
+            .collect();
+        (state, areas)
+    }
+
+    #[test]
+    fn test_two_images() {
+        let (state, areas) = two_images();
+
+        // uploaded to
+        assert_eq!(upload_image_num(Some(&areas), 3).unwrap(), 3);
+        assert_eq!(upload_image_num(Some(&areas), 1).unwrap(), 1);
+        let e = upload_image_num(Some(&areas), 2).unwrap_err();
+        assert_eq!(e.to_string(), "slot 2 cannot be uploaded to");
+        // without slot info
+        assert_eq!(upload_image_num(None, 1).unwrap(), 0);
+        assert_eq!(upload_image_num(None, 3).unwrap(), 3);
+
+        // confirmed and verified
+        for slot in 0..4 {
+            let hash = vec![slot; 32];
+            assert_eq!(slot_hash_to_confirm(&state, slot).unwrap(), hash);
+            let listed: Vec<_> = in_slot(&state, slot).collect();
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].hash, hash);
+        }
+    }
+
+    #[test]
+    fn test_confirm_slot() {
+        let mut state = list(&test_specs()).unwrap();
+        assert_eq!(
+            slot_hash_to_confirm(&state, 0).unwrap(),
+            state.images[0].hash
+        );
+        let e = slot_hash_to_confirm(&state, 1).unwrap_err();
+        assert_eq!(e.to_string(), "slot 1 is empty");
+
+        state.images[0].confirmed = true;
+        let e = slot_hash_to_confirm(&state, 0).unwrap_err();
+        assert_eq!(e.to_string(), "image in slot 0 is already confirmed");
+
+        let state = confirm_slot(&test_specs(), 0).unwrap();
+        assert!(state.images[0].confirmed);
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let fallback = Duration::from_millis(200);
+        let mut round_trips = RoundTrips::new();
+        for ms in [10, 12, 11] {
+            round_trips.record(Duration::from_millis(ms));
+        }
+        assert_eq!(round_trips.timeout(fallback), fallback);
+        round_trips.record(Duration::from_millis(90));
+        assert_eq!(round_trips.timeout(fallback), Duration::from_millis(36));
+        for _ in 0..RTT_WINDOW {
+            round_trips.record(Duration::from_millis(1));
+        }
+        assert_eq!(round_trips.samples.len(), RTT_WINDOW);
+        assert_eq!(round_trips.timeout(fallback), RTT_MIN_TIMEOUT);
+    }
+
+    #[test]
+    fn test_acked_hash() {
+        let data: Vec<u8> = (0..=255).collect();
+        let image_sha = Sha256::digest(&data).to_vec();
+
+        let mut acked = AckedHash::new();
+        acked.ack(&data, 0, &data[0..100], 100).unwrap();
+        acked.ack(&data, 100, &data[100..200], 150).unwrap();
+        // resent from an earlier offset
+        acked.ack(&data, 150, &data[150..250], 120).unwrap();
+        acked.ack(&data, 120, &data[120..], 256).unwrap();
+        acked.verify(&image_sha).unwrap();
+
+        // acknowledged past the data sent
+        let mut acked = AckedHash::new();
+        acked.ack(&data, 0, &data[0..100], 120).unwrap();
+        acked.ack(&data, 120, &data[120..], 256).unwrap();
+        let e = acked.verify(&image_sha).unwrap_err();
+        assert!(e.to_string().ends_with("diverging at offset 100"));
+
+        // a chunk sent at the wrong offset
+        let mut acked = AckedHash::new();
+        acked.ack(&data, 0, &data[0..100], 100).unwrap();
+        acked.ack(&data, 100, &data[110..210], 200).unwrap();
+        acked.ack(&data, 200, &data[200..], 256).unwrap();
+        let e = acked.verify(&image_sha).unwrap_err();
+        assert!(e.to_string().ends_with("before offset 256"));
+
+        let mut acked = AckedHash::new();
+        acked.ack(&data, 0, &data[0..100], 100).unwrap();
+        let e = acked.verify(&image_sha).unwrap_err();
+        assert!(e.to_string().ends_with("before offset 100"));
+    }
+
+    // device answering each upload request with the offset returned by
+    // `ack` for the offset and length of the data received
+    struct ScriptedUpload {
+        ack: fn(usize, usize) -> usize,
+        // offset from which requests get an SMP v2 error, in a v2 header
+        err_at: Option<usize>,
+        // offset whose first answer has a wrong checksum
+        bad_crc_at: Option<usize>,
+        received: Vec<(usize, usize)>,
+        tx: Vec<u8>,
+        rx: Cursor<Vec<u8>>,
+    }
+
+    impl ScriptedUpload {
+        fn new(ack: fn(usize, usize) -> usize) -> ScriptedUpload {
+            ScriptedUpload {
+                ack,
+                err_at: None,
+                bad_crc_at: None,
+                received: Vec::new(),
+                tx: Vec::new(),
+                rx: Cursor::new(Vec::new()),
+            }
+        }
+    }
+
+    // upload `data` to a scripted device, with the offset and length of the
+    // data in each request it received
+    fn scripted_upload(
+        device: ScriptedUpload,
+        data: &dyn ImageSource,
+    ) -> (Result<UploadStats, Error>, Vec<(usize, usize)>) {
+        scripted_upload_with(device, &SerialSpecs::default(), data)
+    }
+
+    fn scripted_upload_with(
+        device: ScriptedUpload,
+        specs: &SerialSpecs,
+        data: &dyn ImageSource,
+    ) -> (Result<UploadStats, Error>, Vec<(usize, usize)>) {
+        let mut port = StreamPort::new(device, Duration::from_secs(1));
+        let options = UploadOptions::default();
+        let result = upload_chunks(&mut port, specs, &options, data, 1, |_| ());
+        (result, port.into_inner().received)
+    }
+
+    impl std::io::Read for ScriptedUpload {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.rx.read(buf)
+        }
+    }
+
+    impl std::io::Write for ScriptedUpload {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.tx.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            let mut base64_data = Vec::new();
+            for line in self.tx.split(|b| *b == b'\n').filter(|l| l.len() > 2) {
+                base64_data.extend_from_slice(&line[2..]);
+            }
+            self.tx.clear();
+            if base64_data.is_empty() {
+                // the newline of a resync
+                return Ok(());
+            }
+            let decoded = general_purpose::STANDARD.decode(base64_data).unwrap();
+            let (header, body) = decode_serial_frame(&decoded).unwrap();
+            let req: BTreeMap<String, Value> = serde_cbor::value::from_value(body).unwrap();
+            let (Value::Integer(off), Value::Bytes(data)) = (&req["off"], &req["data"]) else {
+                panic!("not an upload request");
+            };
+            self.received.push((*off as usize, data.len()));
+            let failed = self.err_at.is_some_and(|at| *off as usize >= at);
+            let mut rsp = BTreeMap::new();
+            if failed {
+                let err = BTreeMap::from([("group", 1), ("rc", 5)]);
+                rsp.insert("err", serde_cbor::value::to_value(err).unwrap());
+            } else {
+                rsp.insert("rc", Value::Integer(0));
+                let off = (self.ack)(*off as usize, data.len());
+                rsp.insert("off", Value::Integer(off as i128));
+            }
+            let body = serde_cbor::to_vec(&rsp).unwrap();
+            let (mut packet, _) = encode_packet(
+                NmpOp::WriteRsp,
+                NmpGroup::Image,
+                NmpIdImage::Upload,
+                &body,
+                header.seq,
+            )
+            .unwrap();
+            if failed {
+                packet[NmpHdr::OP_OFFSET] |= 1 << 3;
+            }
+            let specs = SerialSpecs::default();
+            let frame = encode_frame(
+                &packet,
+                specs.linelength,
+                specs.start_marker,
+                specs.continuation_marker,
+            )
+            .unwrap();
+            let mut frame = frame;
+            if self.bad_crc_at == Some(*off as usize) {
+                self.bad_crc_at = None;
+                // a changed base64 digit in the NMP header
+                frame[8] = if frame[8] == b'A' { b'B' } else { b'A' };
+            }
+            self.rx = Cursor::new(frame);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_upload_skipped_data() {
+        let data = vec![0x5a; 2000];
+        let upload_to = |ack| scripted_upload(ScriptedUpload::new(ack), &data).0;
+        assert!(upload_to(|off, len| off + len).is_ok());
+
+        // the device skips 10 bytes after the first chunk
+        let skip = |off, len| match off {
+            0 => len + 10,
+            _ => min(off + len, 2000),
+        };
+        let e = upload_to(skip).unwrap_err().to_string();
+        assert!(e.starts_with("device acknowledged data it was not sent"));
+    }
+
+    #[test]
+    fn test_upload_v2_error() {
+        // the device fails the second chunk with an SMP v2 group error
+        let mut device = ScriptedUpload::new(|off, len| off + len);
+        device.err_at = Some(1);
+        let (result, received) = scripted_upload(device, &vec![0x5a; 2000]);
+        let e = result.unwrap_err();
+        assert_eq!(e.to_string(), "group = 1, rc = 5");
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(McuMgrError::DeviceError {
+                group: Some(1),
+                rc: 5
+            })
+        ));
+        assert_eq!(received.len(), 2);
+    }
+
+    #[test]
+    fn test_upload_retry_policy() {
+        let data = vec![0x5a; 2000];
+        let device = |at| {
+            let mut device = ScriptedUpload::new(|off, len| off + len);
+            device.bad_crc_at = Some(at);
+            device
+        };
+        let (result, received) = scripted_upload(device(0), &data);
+        assert_eq!(result.unwrap().retransmits, 1);
+        assert_eq!(received[0], received[1]);
+        let next = received[2].0;
+
+        // the answer to the second chunk is corrupted, it is sent again
+        let (result, received) = scripted_upload(device(next), &data);
+        assert_eq!(result.unwrap().retransmits, 1);
+        assert_eq!(received[1], received[2]);
+
+        // without retries in the specs, the upload fails on it
+        let specs = SerialSpecs {
+            nb_retry: 0,
+            ..Default::default()
+        };
+        let (result, received) = scripted_upload_with(device(next), &specs, &data);
+        let e = result.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(McuMgrError::Crc)));
+        assert_eq!(received.len(), 2);
+    }
+
+    #[test]
+    fn test_upload_rsp_version() {
+        let err = BTreeMap::from([("group", 1), ("rc", 5)]);
+        let rsp = BTreeMap::from([("err", err)]);
+        let body = serde_cbor::value::to_value(rsp).unwrap();
+        let mut header = NmpHdr::new_req(NmpOp::WriteRsp, NmpGroup::Image, NmpIdImage::Upload);
+
+        // only an SMP v2 header has the err map looked at, as for other commands
+        assert!(parse_upload_rsp(&header, body.clone()).is_ok());
+        header.version = 1;
+        let e = parse_upload_rsp(&header, body).unwrap_err();
+        assert_eq!(e.to_string(), "group = 1, rc = 5");
+    }
+
+    #[test]
+    fn test_upload_chunk_size() {
+        let data = vec![0x5a; 2000];
+        let (result, received) = scripted_upload(ScriptedUpload::new(|off, len| off + len), &data);
+        let stats = result.unwrap();
+        // the data left after the header and the framing, not the MTU
+        assert!(stats.chunk_size < SerialSpecs::default().mtu);
+        // the first chunk also carries the length and the hash of the image
+        assert!(received[0].1 < stats.chunk_size);
+        assert_eq!(stats.chunk_size, received[received.len() - 2].1);
+        assert_eq!(stats.chunks as usize, received.len());
+
+        let device = ScriptedUpload::new(|off, len| off + len);
+        let (result, received) = scripted_upload(device, &vec![0x5a; 100]);
+        assert_eq!(received, [(0, 100)]);
+        assert_eq!(result.unwrap().chunk_size, 100);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_upload_offset_above_4gib() {
+        // image of zeros, too large to hold in memory
+        struct LargeImage(usize);
+
+        impl ImageSource for LargeImage {
+            fn size(&self) -> usize {
+                self.0
+            }
+
+            fn read_at(&self, _off: usize, len: usize) -> Result<Vec<u8>, Error> {
+                Ok(vec![0; len])
+            }
+
+            fn sha256(&self) -> Result<Vec<u8>, Error> {
+                Ok(vec![0; 32])
+            }
+        }
+
+        // the device acknowledges the first chunk up to the last one
+        let image = LargeImage(5 << 30);
+        let device = ScriptedUpload::new(|off, len| match off {
+            0 => (5 << 30) - 100,
+            _ => off + len,
+        });
+        let (result, received) = scripted_upload(device, &image);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1], ((5 << 30) - 100, 100));
+        assert!(received[1].0 > u32::MAX as usize);
+        // only the skipped data fails the upload, not the offset
+        let e = result.unwrap_err().to_string();
+        assert!(e.starts_with("device acknowledged data it was not sent"));
+    }
+
+    #[test]
+    fn test_upload_stats_display() {
+        let stats = UploadStats {
+            bytes: 1258291,
+            duration: Duration::from_secs(34),
+            chunk_size: 512,
+            chunks: 2458,
+            retransmits: 3,
+        };
+        assert_eq!(
+            stats.to_string(),
+            "1.2 MiB in 34s (36 KiB/s), 512 B chunks, 3 retransmits"
+        );
+    }
+
+    #[test]
+    fn test_pad_to_alignment() {
+        let mut data = vec![1; 10];
+        pad_to_alignment(&mut data, 8).unwrap();
+        assert_eq!(data.len(), 16);
+        assert_eq!(data[9..], [1, 0, 0, 0, 0, 0, 0]);
+        pad_to_alignment(&mut data, 8).unwrap();
+        assert_eq!(data.len(), 16);
+        assert!(pad_to_alignment(&mut data, 0).is_err());
+    }
+
+    #[test]
+    fn test_stream_image() {
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let file = TempFile::new(&data);
+        let image = FileImage::open(file.path(), Some(64)).unwrap();
+        assert_eq!(image.size(), 100_032);
+        assert_eq!(
+            image.read_at(99_998, 4).unwrap(),
+            [data[99_998], data[99_999], 0, 0]
+        );
+        let mut padded = data.clone();
+        pad_to_alignment(&mut padded, 64).unwrap();
+        assert_eq!(image.sha256().unwrap(), padded.sha256().unwrap());
+
+        let options = UploadOptions {
+            stream_image: true,
+            ..Default::default()
+        };
+        let result = upload(
+            &test_specs(),
+            &options,
+            file.path(),
+            Slot::Secondary,
+            None::<fn(u64, u64)>,
+        );
+        assert_eq!(result.unwrap().bytes, 100_000);
+    }
+
+    #[test]
+    fn test_check_offset() {
+        assert!(check_offset(0, 100).is_ok());
+        assert!(check_offset(100, 200).is_ok());
+        let e = check_offset(200, 0).unwrap_err();
+        assert_eq!(e.to_string(), "device appears to have reset during upload");
+        assert!(check_offset(200, 200).is_err());
+    }
+
+    #[test]
+    fn test_mark_confirmed_running() {
+        let state = mark_confirmed(&test_specs(), None).unwrap();
+        assert!(state.images[0].confirmed);
+    }
+
+    #[test]
+    fn test_mark_confirmed_unknown_hash() {
+        assert!(mark_confirmed(&test_specs(), Some(vec![0; 32])).is_err());
+    }
+
+    #[test]
+    fn test_warn_before_confirm() {
+        let specs = test_specs();
+        let mut port = open_port(&specs).unwrap();
+        let warning = warn_before_confirm(&mut *port, &specs, &[0; 32]).unwrap();
+        assert!(warning.unwrap().contains("not found"));
+
+        let hash = list(&specs).unwrap().images[0].hash.clone();
+        let mut port = open_port(&specs).unwrap();
+        let warning = warn_before_confirm(&mut *port, &specs, &hash).unwrap();
+        assert!(warning.is_none());
+        assert!(mark_confirmed(&specs, Some(hash)).is_ok());
+    }
 }
@@ -1,9 +1,52 @@
+#[cfg(feature = "serial")]
+mod client;
+#[cfg(feature = "serial")]
 mod default;
+#[cfg(feature = "serial")]
 mod image;
-mod nmp_hdr;
-mod transfer;
+pub mod mcuboot;
+#[cfg(feature = "mem-read")]
+mod mem;
+pub mod nmp_hdr;
+#[cfg(feature = "serial")]
+mod probe;
+pub mod protocol;
+#[cfg(feature = "serial")]
+mod stream_port;
+#[cfg(feature = "serial")]
 mod test_serial_port;
+#[cfg(feature = "serial")]
+mod transfer;
+mod version;
 
-pub use crate::default::reset;
-pub use crate::image::{list, upload, test, erase};
-pub use crate::transfer::SerialSpecs;
\ No newline at end of file
+#[cfg(feature = "serial")]
+pub use crate::client::{batch_upload, Client, Transport};
+#[cfg(feature = "serial")]
+pub use crate::default::{
+    app_info, exit_recovery, mcumgr_params, reset, reset_on_port, reset_reason, safe_reset,
+    smp_version,
+};
+#[cfg(feature = "serial")]
+pub use crate::image::{
+    confirm_hex, confirm_slot, erase, erase_all, estimate_upload, find_slot_by_hash,
+    find_slot_by_version, flash_area_info, flash_verify, list, list_hashes, list_on_port,
+    mark_confirmed, mark_confirmed_on_port, parse_hash, recovery_status, running_hash, test,
+    test_hex, test_on_port, upload, upload_events, upload_on_port, FlashArea, FlashVerifyStats,
+    Slot, SlotResults, UploadEstimate, UploadOptions, UploadProgress, UploadStats,
+};
+pub use crate::mcuboot::{mcuboot_image_hash, parse_mcuboot_tlvs, Tlv};
+#[cfg(feature = "mem-read")]
+pub use crate::mem::{mem_read, MemReadCmd};
+#[cfg(feature = "serial")]
+pub use crate::probe::{
+    capabilities, detect_context, probe, Capabilities, DeviceContext, DeviceProbe,
+};
+pub use crate::protocol::{check_rc, check_rc_version, MalformedResponse, McuMgrError};
+#[cfg(feature = "serial")]
+pub use crate::stream_port::StreamPort;
+#[cfg(feature = "serial")]
+pub use crate::transfer::{
+    open_port, transceive_until, transceive_with, transceive_with_retry, transceive_with_retry_raw,
+    RawResponse, RetryPolicy, SerialSpecs,
+};
+pub use crate::version::{ImageVersion, MCUBOOT_IMAGE_MAGIC};
@@ -1,21 +1,22 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
 use anyhow::{Error, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{error, info, LevelFilter};
-use serialport::available_ports;
+use serialport::{available_ports, DataBits, FlowControl, Parity, StopBits};
 use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
 use std::env;
 use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 
 use mcumgr_client::*;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// device name
+    /// device name, or tcp://host:port for a serial console forwarded over TCP
     #[arg(short, long, default_value = "")]
     device: String,
 
@@ -47,10 +48,135 @@ struct Cli {
     #[arg(short, long, default_value_t = 115_200)]
     baudrate: u32,
 
+    /// number of data bits
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(5..=8))]
+    data_bits: u8,
+
+    /// parity
+    #[arg(long, value_enum, default_value_t = CliParity::None)]
+    parity: CliParity,
+
+    /// number of stop bits
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=2))]
+    stop_bits: u8,
+
+    /// flow control
+    #[arg(long, value_enum, default_value_t = CliFlowControl::None)]
+    flow_control: CliFlowControl,
+
+    /// set the DTR line after opening the port
+    #[arg(long)]
+    dtr: Option<bool>,
+
+    /// set the RTS line after opening the port
+    #[arg(long)]
+    rts: Option<bool>,
+
+    /// flush a partial frame from a previous session before the command
+    #[arg(long)]
+    resync: bool,
+
+    /// send request maps with the keys in canonical CBOR order, for strict devices
+    #[arg(long)]
+    canonical_cbor: bool,
+
+    /// wait this many msec after sending a request before reading the response
+    #[arg(long, default_value_t = 0)]
+    response_delay: u64,
+
+    /// after opening the port, wait for the device to print this prompt before sending requests
+    #[arg(long)]
+    expect_prompt: Option<String>,
+
+    /// how long to wait for the prompt, e.g. "5s"
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+    prompt_timeout: Duration,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum CliParity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CliFlowControl {
+    None,
+    Software,
+    Hardware,
+}
+
+#[derive(Args)]
+struct CliUploadOptions {
+    /// maximum number of retries for a whole upload
+    #[arg(long)]
+    max_total_retries: Option<u32>,
+
+    /// maximum length per request when uploading to MCUboot serial recovery
+    #[arg(long)]
+    recovery_mtu: Option<usize>,
+
+    /// adapt the chunk size to what the link handles
+    #[arg(long)]
+    adaptive_chunk: bool,
+
+    /// derive the timeout from the measured round trip time instead of subsequent_timeout_ms
+    #[arg(long)]
+    adaptive_timeout: bool,
+
+    /// log upload progress at this interval, e.g. "5s"
+    #[arg(long, value_parser = humantime::parse_duration)]
+    log_interval: Option<Duration>,
+
+    /// zero-pad the uploaded image to a multiple of this flash write size
+    #[arg(long)]
+    align: Option<usize>,
+
+    /// send the sha of each chunk instead of the sha of the whole image
+    #[arg(long)]
+    chunk_sha: bool,
+
+    /// erase the target slot if an upload fails, so a retry starts clean
+    #[arg(long)]
+    cleanup_on_failure: bool,
+
+    /// refuse to upload an image larger than this many bytes
+    #[arg(long)]
+    max_image_size: Option<usize>,
+
+    /// before uploading, check that the image fits the slot, if the device reports slot sizes
+    #[arg(long)]
+    check_slot_size: bool,
+
+    /// read the image from the file chunk by chunk while uploading, instead of loading it whole
+    #[arg(long)]
+    stream_image: bool,
+}
+
+impl From<&CliUploadOptions> for UploadOptions {
+    fn from(cli: &CliUploadOptions) -> UploadOptions {
+        UploadOptions {
+            max_total_retries: cli.max_total_retries,
+            recovery_mtu: cli.recovery_mtu,
+            adaptive_chunk: cli.adaptive_chunk,
+            adaptive_timeout: cli.adaptive_timeout,
+            log_interval: cli.log_interval,
+            align: cli.align,
+            chunk_sha: cli.chunk_sha,
+            reset_after: false,
+            cleanup_on_failure: cli.cleanup_on_failure,
+            max_image_size: cli.max_image_size,
+            check_slot_size: cli.check_slot_size,
+            stream_image: cli.stream_image,
+            round_trip: None,
+        }
+    }
+}
+
 impl From<&Cli> for SerialSpecs {
     fn from(cli: &Cli) -> SerialSpecs {
         SerialSpecs {
@@ -61,6 +187,34 @@ impl From<&Cli> for SerialSpecs {
             linelength: cli.linelength,
             mtu: cli.mtu,
             baudrate: cli.baudrate,
+            data_bits: match cli.data_bits {
+                5 => DataBits::Five,
+                6 => DataBits::Six,
+                7 => DataBits::Seven,
+                _ => DataBits::Eight,
+            },
+            parity: match cli.parity {
+                CliParity::None => Parity::None,
+                CliParity::Odd => Parity::Odd,
+                CliParity::Even => Parity::Even,
+            },
+            stop_bits: match cli.stop_bits {
+                2 => StopBits::Two,
+                _ => StopBits::One,
+            },
+            flow_control: match cli.flow_control {
+                CliFlowControl::None => FlowControl::None,
+                CliFlowControl::Software => FlowControl::Software,
+                CliFlowControl::Hardware => FlowControl::Hardware,
+            },
+            dtr: cli.dtr,
+            rts: cli.rts,
+            resync: cli.resync,
+            canonical_cbor: cli.canonical_cbor,
+            response_delay_ms: cli.response_delay,
+            expect_prompt: cli.expect_prompt.clone(),
+            prompt_timeout: cli.prompt_timeout,
+            ..Default::default()
         }
     }
 }
@@ -70,16 +224,48 @@ enum Commands {
     /// list slots on the device
     List,
 
+    /// show which groups of commands the device supports
+    Capabilities,
+
+    /// show the size of each slot, if the device supports it
+    SlotInfo,
+
     /// reset the device
-    Reset,
+    Reset {
+        /// refuse to reset when a pending image has no confirmed image to revert to
+        #[arg(long)]
+        safe: bool,
+
+        /// with --safe, only warn and reset anyway
+        #[arg(long, requires = "safe")]
+        force: bool,
+
+        /// ask the device to wait this many msec before resetting, if it supports it
+        #[arg(long)]
+        reset_delay: Option<u32>,
+    },
+
+    /// show the MTU, parameters, application info and images of the device
+    Probe,
 
     /// upload a file to the device
     Upload {
         filename: PathBuf,
 
-        /// slot number
-        #[arg(short, long, default_value_t = 1)]
-        slot: u8,
+        /// slot number, or primary or secondary
+        #[arg(short, long, default_value_t = Slot::Secondary)]
+        slot: Slot,
+
+        /// reset the device after a successful upload
+        #[arg(long)]
+        reset: bool,
+
+        /// with --reset, ask the device to wait this many msec before resetting, if it supports it
+        #[arg(long, requires = "reset")]
+        reset_delay: Option<u32>,
+
+        #[command(flatten)]
+        options: CliUploadOptions,
     },
 
     Test {
@@ -88,8 +274,22 @@ enum Commands {
         confirm: Option<bool>,
     },
     Erase {
+        /// slot number, or primary or secondary
         #[arg(short, long)]
-        slot: Option<u32>,
+        slot: Option<Slot>,
+
+        /// image number, for devices with more than one image
+        #[arg(short, long)]
+        image: Option<u32>,
+    },
+
+    /// confirm the running image, or the image with the given hash or in the given slot
+    Confirm {
+        hash: Option<String>,
+
+        /// confirm the image in this slot, instead of giving its hash
+        #[arg(long, conflicts_with = "hash")]
+        slot: Option<u8>,
     },
 }
 
@@ -98,7 +298,7 @@ fn main() {
     let name = env!("CARGO_PKG_NAME");
     let version = env!("CARGO_PKG_VERSION");
     println!("{} {}, Copyright © 2024 Vouch.io LLC", name, version);
-    println!("");
+    println!();
 
     // parse command line arguments
     let mut cli = Cli::parse();
@@ -120,21 +320,18 @@ fn main() {
     // if no device is specified, try to auto detect it
     if cli.device.is_empty() {
         let mut bootloaders = Vec::new();
-        match available_ports() {
-            Ok(ports) => {
-                for port in ports {
-                    let name = port.port_name;
-                    // on Mac, use only special names
-                    if env::consts::OS == "macos" {
-                        if name.contains("cu.usbmodem") {
-                            bootloaders.push(name);
-                        }
-                    } else {
+        if let Ok(ports) = available_ports() {
+            for port in ports {
+                let name = port.port_name;
+                // on Mac, use only special names
+                if env::consts::OS == "macos" {
+                    if name.contains("cu.usbmodem") {
                         bootloaders.push(name);
                     }
+                } else {
+                    bootloaders.push(name);
                 }
             }
-            Err(_) => {}
         }
 
         // if there is one bootloader device, then use it
@@ -186,37 +383,85 @@ fn main() {
             print!("response: {}", serde_json::to_string_pretty(&v)?);
             Ok(())
         }(),
-        Commands::Reset => reset(&specs),
-        Commands::Upload { filename, slot } => || -> Result<(), Error> {
+        Commands::Capabilities => capabilities(&specs).map(|c| print!("{}", c)),
+        Commands::SlotInfo => || -> Result<(), Error> {
+            let v = flash_area_info(&specs)?;
+            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            Ok(())
+        }(),
+        Commands::Reset {
+            safe,
+            force,
+            reset_delay,
+        } => {
+            let specs = SerialSpecs {
+                reset_delay_ms: *reset_delay,
+                ..specs.clone()
+            };
+            if *safe {
+                safe_reset(&specs, *force)
+            } else {
+                reset(&specs)
+            }
+        }
+        Commands::Probe => || -> Result<(), Error> {
+            let v = probe(&specs)?;
+            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            Ok(())
+        }(),
+        Commands::Upload {
+            filename,
+            slot,
+            reset,
+            reset_delay,
+            options,
+        } => {
+            let specs = SerialSpecs {
+                reset_delay_ms: *reset_delay,
+                ..specs.clone()
+            };
+            let options = UploadOptions {
+                reset_after: *reset,
+                ..options.into()
+            };
+
             // create a progress bar
-            let pb = ProgressBar::new(1 as u64);
+            let pb = ProgressBar::new(1);
             pb.set_style(ProgressStyle::default_bar()
             .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
             .unwrap().progress_chars("=> "));
 
             upload(
                 &specs,
+                &options,
                 filename,
                 *slot,
                 Some(|offset, total| {
                     if let Some(l) = pb.length() {
                         if l != total {
-                            pb.set_length(total as u64)
+                            pb.set_length(total)
                         }
                     }
 
-                    pb.set_position(offset as u64);
+                    pb.set_position(offset);
 
                     if offset >= total {
                         pb.finish_with_message("upload complete");
                     }
                 }),
             )
+            .map(|_| ())
+        }
+        Commands::Test { hash, confirm } => test_hex(&specs, hash, *confirm),
+        Commands::Erase { slot, image } => erase(&specs, *slot, *image),
+        Commands::Confirm {
+            slot: Some(slot), ..
+        } => confirm_slot(&specs, *slot).map(|_| ()),
+        Commands::Confirm { hash, slot: None } => || -> Result<(), Error> {
+            let hash = hash.as_deref().map(parse_hash).transpose()?;
+            mark_confirmed(&specs, hash)?;
+            Ok(())
         }(),
-        Commands::Test { hash, confirm } => || -> Result<(), Error> { 
-            test(&specs, hex::decode(hash)?, *confirm)
-        }(),
-        Commands::Erase { slot } => erase(&specs, *slot),
     };
 
     // show error, if failed
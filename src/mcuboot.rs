@@ -0,0 +1,262 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+// TLV area of MCUboot images: the hash, the signature and the other metadata
+// appended by imgtool after the image payload.
+
+use anyhow::{bail, Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(feature = "serial")]
+use std::cmp::min;
+
+use crate::version::MCUBOOT_IMAGE_MAGIC;
+
+/// magic of the unprotected TLV area
+pub const TLV_INFO_MAGIC: u16 = 0x6907;
+
+/// magic of the protected TLV area, covered by the image hash
+pub const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+
+// offsets in the MCUboot image header
+const HDR_SIZE_OFFSET: usize = 8;
+const IMG_SIZE_OFFSET: usize = 12;
+const HDR_MIN_LEN: usize = 32;
+
+// TLV info and TLV entry headers: magic or type, then length
+const TLV_HDR_LEN: usize = 4;
+
+/// one entry of the TLV area
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlv {
+    pub kind: u16,
+    pub data: Vec<u8>,
+    /// in the protected area, covered by the image hash and signature
+    pub protected: bool,
+}
+
+impl Tlv {
+    /// imgtool name of the TLV type
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            0x01 => "KEYHASH",
+            0x02 => "PUBKEY",
+            0x10 => "SHA256",
+            0x11 => "SHA384",
+            0x12 => "SHA512",
+            0x20 => "RSA2048",
+            0x21 => "ECDSA224",
+            0x22 => "ECDSA256",
+            0x23 => "RSA3072",
+            0x24 => "ED25519",
+            0x25 => "SIG_PURE",
+            0x30 => "ENCRSA2048",
+            0x31 => "ENCKW",
+            0x32 => "ENCEC256",
+            0x33 => "ENCX25519",
+            0x40 => "DEPENDENCY",
+            0x50 => "SEC_CNT",
+            0x60 => "BOOT_RECORD",
+            _ => "UNKNOWN",
+        }
+    }
+
+    pub fn is_signature(&self) -> bool {
+        (0x20..=0x25).contains(&self.kind)
+    }
+
+    pub fn is_hash(&self) -> bool {
+        (0x10..=0x12).contains(&self.kind)
+    }
+
+    /// the wrapped key of an encrypted image
+    pub fn is_encryption_key(&self) -> bool {
+        (0x30..=0x33).contains(&self.kind)
+    }
+}
+
+// walk the entries of one TLV area, returns them and the offset after the
+// area. `data` starts at offset `base` of the image, for the error messages.
+fn parse_area(
+    data: &[u8],
+    off: usize,
+    magic: u16,
+    protected: bool,
+    base: usize,
+) -> Result<Option<(Vec<Tlv>, usize)>, Error> {
+    if data.len() < off + TLV_HDR_LEN || LittleEndian::read_u16(&data[off..]) != magic {
+        return Ok(None);
+    }
+    let total = LittleEndian::read_u16(&data[off + 2..]) as usize;
+    let end = off + total;
+    if total < TLV_HDR_LEN || end > data.len() {
+        bail!("TLV area at {:#x} exceeds the image", base + off);
+    }
+
+    let mut tlvs = Vec::new();
+    let mut pos = off + TLV_HDR_LEN;
+    while pos < end {
+        if pos + TLV_HDR_LEN > end {
+            bail!("truncated TLV at {:#x}", base + pos);
+        }
+        let kind = LittleEndian::read_u16(&data[pos..]);
+        let len = LittleEndian::read_u16(&data[pos + 2..]) as usize;
+        let start = pos + TLV_HDR_LEN;
+        if start + len > end {
+            bail!("TLV at {:#x} exceeds its area", base + pos);
+        }
+        tlvs.push(Tlv {
+            kind,
+            data: data[start..start + len].to_vec(),
+            protected,
+        });
+        pos = start + len;
+    }
+    Ok(Some((tlvs, end)))
+}
+
+// offset of the TLV areas, after the header and the payload
+fn tlv_offset(header: &[u8]) -> Result<usize, Error> {
+    if header.len() < HDR_MIN_LEN {
+        bail!("image too short for an MCUboot header");
+    }
+    if LittleEndian::read_u32(header) != MCUBOOT_IMAGE_MAGIC {
+        bail!("not an MCUboot image, wrong header magic");
+    }
+    let hdr_size = LittleEndian::read_u16(&header[HDR_SIZE_OFFSET..]) as usize;
+    let img_size = LittleEndian::read_u32(&header[IMG_SIZE_OFFSET..]) as usize;
+    Ok(hdr_size + img_size)
+}
+
+// the TLVs of the areas at the start of `data`, which is at offset `base`
+fn parse_tlv_areas(data: &[u8], base: usize) -> Result<Vec<Tlv>, Error> {
+    let mut off = 0;
+    let mut tlvs = Vec::new();
+    if let Some((protected, end)) = parse_area(data, off, TLV_PROT_INFO_MAGIC, true, base)? {
+        tlvs.extend(protected);
+        off = end;
+    }
+    match parse_area(data, off, TLV_INFO_MAGIC, false, base)? {
+        Some((unprotected, _)) => tlvs.extend(unprotected),
+        None => bail!("no TLV area at {:#x}", base + off),
+    }
+    Ok(tlvs)
+}
+
+/// Read the TLVs after the payload of an MCUboot image: the protected ones
+/// first, if any, then the unprotected ones with the hash and signature.
+pub fn parse_mcuboot_tlvs(data: &[u8]) -> Result<Vec<Tlv>, Error> {
+    let off = tlv_offset(data)?;
+    parse_tlv_areas(data.get(off..).unwrap_or_default(), off)
+}
+
+fn hash_tlv(tlvs: Vec<Tlv>) -> Result<Vec<u8>, Error> {
+    match tlvs.into_iter().find(|tlv| tlv.is_hash()) {
+        Some(tlv) => Ok(tlv.data),
+        None => bail!("no hash TLV in the image"),
+    }
+}
+
+/// image hash, as `list` reports it for the slot holding the image
+pub fn mcuboot_image_hash(data: &[u8]) -> Result<Vec<u8>, Error> {
+    hash_tlv(parse_mcuboot_tlvs(data)?)
+}
+
+/// `mcuboot_image_hash` of an image of `size` bytes read with `read_at`,
+/// given an offset and a length. Only the header and the TLVs are read.
+#[cfg(feature = "serial")]
+pub(crate) fn mcuboot_image_hash_by<F>(size: usize, read_at: F) -> Result<Vec<u8>, Error>
+where
+    F: Fn(usize, usize) -> Result<Vec<u8>, Error>,
+{
+    let off = tlv_offset(&read_at(0, min(HDR_MIN_LEN, size))?)?;
+    let tlv_data = match size.checked_sub(off) {
+        Some(len) => read_at(off, len)?,
+        None => Vec::new(),
+    };
+    hash_tlv(parse_tlv_areas(&tlv_data, off)?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn tlv_area(magic: u16, tlvs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for (kind, data) in tlvs {
+            entries.extend_from_slice(&kind.to_le_bytes());
+            entries.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            entries.extend_from_slice(data);
+        }
+        let mut area = magic.to_le_bytes().to_vec();
+        area.extend_from_slice(&((entries.len() + TLV_HDR_LEN) as u16).to_le_bytes());
+        area.extend(entries);
+        area
+    }
+
+    // image with a 32 byte header and a 16 byte payload
+    pub(crate) fn test_image(protected: &[(u16, &[u8])], tlvs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut image = vec![0u8; 48];
+        LittleEndian::write_u32(&mut image, MCUBOOT_IMAGE_MAGIC);
+        LittleEndian::write_u16(&mut image[HDR_SIZE_OFFSET..], 32);
+        LittleEndian::write_u32(&mut image[IMG_SIZE_OFFSET..], 16);
+        if !protected.is_empty() {
+            image.extend(tlv_area(TLV_PROT_INFO_MAGIC, protected));
+        }
+        image.extend(tlv_area(TLV_INFO_MAGIC, tlvs));
+        image
+    }
+
+    #[test]
+    fn test_parse_tlvs() {
+        let image = test_image(
+            &[(0x50, &[1, 0, 0, 0])],
+            &[
+                (0x10, &[0xaa; 32]),
+                (0x01, &[0xbb; 32]),
+                (0x22, &[0xcc; 72]),
+            ],
+        );
+        let tlvs = parse_mcuboot_tlvs(&image).unwrap();
+        assert_eq!(tlvs.len(), 4);
+        assert!(tlvs[0].protected);
+        assert_eq!(tlvs[0].name(), "SEC_CNT");
+        assert!(tlvs[1].is_hash() && !tlvs[1].protected);
+        assert_eq!(tlvs[2].name(), "KEYHASH");
+        assert!(tlvs[3].is_signature());
+        assert_eq!(tlvs[3].data.len(), 72);
+    }
+
+    #[test]
+    fn test_encryption_key() {
+        let image = test_image(&[], &[(0x10, &[0xaa; 32]), (0x33, &[0xdd; 113])]);
+        let tlvs = parse_mcuboot_tlvs(&image).unwrap();
+        assert!(!tlvs[0].is_encryption_key());
+        assert!(tlvs[1].is_encryption_key());
+        assert_eq!(tlvs[1].name(), "ENCX25519");
+    }
+
+    #[cfg(feature = "serial")]
+    #[test]
+    fn test_image_hash_by() {
+        let image = test_image(&[(0x50, &[1, 0, 0, 0])], &[(0x10, &[0xaa; 32])]);
+        let read = std::cell::RefCell::new(0);
+        let read_at = |off: usize, len: usize| {
+            *read.borrow_mut() += len;
+            Ok(image[off..off + len].to_vec())
+        };
+        let hash = mcuboot_image_hash_by(image.len(), read_at).unwrap();
+        assert_eq!(hash, [0xaa; 32]);
+        // the header and the TLVs, not the payload
+        assert_eq!(*read.borrow(), image.len() - 16);
+
+        let e = mcuboot_image_hash_by(40, read_at).unwrap_err();
+        assert_eq!(e.to_string(), "no TLV area at 0x30");
+    }
+
+    #[test]
+    fn test_parse_tlvs_invalid() {
+        let image = test_image(&[], &[(0x10, &[0xaa; 32])]);
+        assert!(parse_mcuboot_tlvs(&image[..image.len() - 1]).is_err());
+        assert!(parse_mcuboot_tlvs(&image[..48]).is_err());
+        assert!(parse_mcuboot_tlvs(&[0; 64]).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+// Memory read for devices with a vendor specific debug command. It is not part
+// of SMP, so the group and id of the command are configurable.
+
+use anyhow::{bail, Error, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+
+use crate::nmp_hdr::*;
+use crate::protocol::check_rc_version;
+use crate::transfer::open_port;
+use crate::transfer::transceive_with_retry;
+use crate::transfer::RetryPolicy;
+use crate::transfer::SerialSpecs;
+
+// room in a response frame for the header, the CBOR map, the length and the CRC
+const MEM_READ_OVERHEAD: usize = NMP_HDR_LEN + 24;
+
+/// group and id of the memory read command
+#[derive(Debug, Clone, Copy)]
+pub struct MemReadCmd {
+    pub group: NmpGroup,
+    pub id: u8,
+}
+
+impl Default for MemReadCmd {
+    fn default() -> MemReadCmd {
+        MemReadCmd {
+            group: NmpGroup::PerUser,
+            id: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MemReadReq {
+    addr: u64,
+    len: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct MemReadRsp {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+// largest read whose base64 encoded response fits in the MTU
+fn page_len(specs: &SerialSpecs) -> Result<usize, Error> {
+    let raw = specs.mtu * 3 / 4;
+    if raw <= MEM_READ_OVERHEAD {
+        bail!("MTU too small");
+    }
+    Ok(raw - MEM_READ_OVERHEAD)
+}
+
+/// Read `len` bytes of device memory at `addr`, in pages that fit the MTU
+pub fn mem_read(
+    specs: &SerialSpecs,
+    cmd: MemReadCmd,
+    addr: u64,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    info!("read {} bytes of memory at {:#x}", len, addr);
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    let page = page_len(specs)?;
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let req = MemReadReq {
+            addr: addr + data.len() as u64,
+            len: min(page, len - data.len()) as u32,
+        };
+        debug!("req: {:?}", req);
+        let (response_header, response_body) = transceive_with_retry(
+            &mut *port,
+            specs,
+            NmpOp::Read,
+            cmd.group,
+            cmd.id,
+            &req,
+            &RetryPolicy::from(specs),
+        )?;
+        check_rc_version(&response_body, Some(response_header.smp_version()))?;
+        let rsp: MemReadRsp = serde_cbor::value::from_value(response_body)
+            .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+        // the device may return less than asked, continue after what it sent
+        if rsp.data.is_empty() {
+            bail!("no data at {:#x}", req.addr);
+        }
+        let take = min(rsp.data.len(), len - data.len());
+        data.extend_from_slice(&rsp.data[..take]);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_serial_port::test_specs;
+
+    #[test]
+    fn test_mem_read_pages() {
+        let specs = SerialSpecs {
+            mtu: 128,
+            ..test_specs()
+        };
+        assert!(page_len(&specs).unwrap() < 100);
+
+        // the test device returns the low byte of each address
+        let data = mem_read(&specs, MemReadCmd::default(), 0x2000_0010, 300).unwrap();
+        assert_eq!(data.len(), 300);
+        assert!(data.iter().enumerate().all(|(i, b)| *b == (0x10 + i) as u8));
+
+        let e = mem_read(&specs, MemReadCmd::default(), 0xffff_fff0, 32).unwrap_err();
+        assert_eq!(e.to_string(), "rc = 3");
+    }
+}
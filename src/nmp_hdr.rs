@@ -1,12 +1,20 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
-use hex_buffer_serde::{Hex as _, HexForm};
+use anyhow::Error;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use num;
+use hex_buffer_serde::{Hex as _, HexForm};
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 
+use crate::protocol::McuMgrError;
+use crate::version::ImageVersion;
+
+/// Length of a serialized NMP header. The header is, in this order and big
+/// endian: op (1 byte), flags (1), body length (2), group (2), sequence
+/// number (1) and command id (1), see the `NmpHdr::*_OFFSET` constants.
+pub const NMP_HDR_LEN: usize = 8;
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, FromPrimitive, PartialEq)]
 pub enum NmpOp {
@@ -26,28 +34,97 @@ pub enum NmpErr {
     EInvalid = 3,
     ETimeout = 4,
     ENoEnt = 5,
+    EBadState = 6,
+    EMsgSize = 7,
+    ENotSup = 8,
 }
 
-#[repr(u16)]
-#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub enum NmpGroup {
-    Default = 0,
-    Image = 1,
-    Stat = 2,
-    Config = 3,
-    Log = 4,
-    Crash = 5,
-    Split = 6,
-    Run = 7,
-    Fs = 8,
-    Shell = 9,
-    PerUser = 64,
+    Default,
+    Image,
+    Stat,
+    Config,
+    Log,
+    Crash,
+    Split,
+    Run,
+    Fs,
+    Shell,
+    PerUser,
+    /// any other group, e.g. a vendor defined one
+    Custom(u16),
+}
+
+impl NmpGroup {
+    pub fn to_u16(self) -> u16 {
+        match self {
+            NmpGroup::Default => 0,
+            NmpGroup::Image => 1,
+            NmpGroup::Stat => 2,
+            NmpGroup::Config => 3,
+            NmpGroup::Log => 4,
+            NmpGroup::Crash => 5,
+            NmpGroup::Split => 6,
+            NmpGroup::Run => 7,
+            NmpGroup::Fs => 8,
+            NmpGroup::Shell => 9,
+            NmpGroup::PerUser => 64,
+            NmpGroup::Custom(group) => group,
+        }
+    }
+
+    /// the named group for a known id, `Custom` for the others
+    pub fn from_u16(group: u16) -> NmpGroup {
+        match group {
+            0 => NmpGroup::Default,
+            1 => NmpGroup::Image,
+            2 => NmpGroup::Stat,
+            3 => NmpGroup::Config,
+            4 => NmpGroup::Log,
+            5 => NmpGroup::Crash,
+            6 => NmpGroup::Split,
+            7 => NmpGroup::Run,
+            8 => NmpGroup::Fs,
+            9 => NmpGroup::Shell,
+            64 => NmpGroup::PerUser,
+            _ => NmpGroup::Custom(group),
+        }
+    }
+}
+
+impl From<NmpGroup> for u16 {
+    fn from(group: NmpGroup) -> u16 {
+        group.to_u16()
+    }
 }
 
+impl From<u16> for NmpGroup {
+    fn from(group: u16) -> NmpGroup {
+        NmpGroup::from_u16(group)
+    }
+}
+
+// groups are equal by id, so Custom(1) is the same group as Image
+impl PartialEq for NmpGroup {
+    fn eq(&self, other: &NmpGroup) -> bool {
+        self.to_u16() == other.to_u16()
+    }
+}
+
+impl Eq for NmpGroup {}
+
 pub trait NmpId {
     fn to_u8(&self) -> u8;
 }
 
+/// raw command id, for commands without an id enum
+impl NmpId for u8 {
+    fn to_u8(&self) -> u8 {
+        *self
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -58,6 +135,8 @@ pub enum NmpIdDef {
     MpStat = 3,
     DateTimeStr = 4,
     Reset = 5,
+    McumgrParams = 6,
+    AppInfo = 7,
 }
 
 impl NmpId for NmpIdDef {
@@ -75,6 +154,7 @@ pub enum NmpIdImage {
     CoreList = 3,
     CoreLoad = 4,
     Erase = 5,
+    SlotInfo = 6,
 }
 
 impl NmpId for NmpIdImage {
@@ -142,6 +222,9 @@ pub enum NmpIdShell {
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub struct NmpHdr {
     pub op: NmpOp,
+    /// SMP version, in the bits above `op`: 0 for SMP v1, 1 for SMP v2
+    #[serde(default)]
+    pub version: u8,
     pub flags: u8,
     pub len: u16,
     pub group: NmpGroup,
@@ -150,9 +233,30 @@ pub struct NmpHdr {
 }
 
 impl NmpHdr {
+    /// length of a serialized header, same as `NMP_HDR_LEN`
+    pub const LEN: usize = NMP_HDR_LEN;
+
+    /// offsets of the fields in a serialized header
+    pub const OP_OFFSET: usize = 0;
+    pub const FLAGS_OFFSET: usize = 1;
+    pub const LEN_OFFSET: usize = 2;
+    pub const GROUP_OFFSET: usize = 4;
+    pub const SEQ_OFFSET: usize = 6;
+    pub const ID_OFFSET: usize = 7;
+
+    /// body length from a serialized header, `None` if `raw` is too short
+    pub fn body_len(raw: &[u8]) -> Option<usize> {
+        if raw.len() < NMP_HDR_LEN {
+            return None;
+        }
+        let len = &raw[NmpHdr::LEN_OFFSET..NmpHdr::LEN_OFFSET + 2];
+        Some(u16::from_be_bytes([len[0], len[1]]) as usize)
+    }
+
     pub fn new_req(op: NmpOp, group: NmpGroup, id: impl NmpId) -> NmpHdr {
         NmpHdr {
             op,
+            version: 0,
             flags: 0,
             len: 0,
             group,
@@ -161,26 +265,65 @@ impl NmpHdr {
         }
     }
 
+    /// SMP version of the packet, 1 or 2
+    pub fn smp_version(&self) -> u8 {
+        self.version + 1
+    }
+
+    /// length of the CBOR body following the header
+    pub fn payload_len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// whether this is the response to the request with header `req`
+    pub fn header_matches(&self, req: &NmpHdr) -> bool {
+        // verify sequence id
+        if self.seq != req.seq {
+            log::debug!("wrong sequence number");
+            return false;
+        }
+
+        let expected_op_type = match req.op {
+            NmpOp::Read => NmpOp::ReadRsp,
+            NmpOp::Write => NmpOp::WriteRsp,
+            _ => return false,
+        };
+
+        // verify response
+        if self.op != expected_op_type || self.group != req.group {
+            log::debug!("wrong response types");
+            return false;
+        }
+
+        true
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>, bincode::Error> {
         let mut buffer = Vec::new();
-        buffer.write_u8(self.op as u8)?;
+        buffer.write_u8((self.version & 0x03) << 3 | self.op as u8)?;
         buffer.write_u8(self.flags)?;
         buffer.write_u16::<BigEndian>(self.len)?;
-        buffer.write_u16::<BigEndian>(self.group as u16)?;
+        buffer.write_u16::<BigEndian>(self.group.to_u16())?;
         buffer.write_u8(self.seq)?;
         buffer.write_u8(self.id)?;
         Ok(buffer)
     }
 
-    pub fn deserialize(cursor: &mut Cursor<&Vec<u8>>) -> Result<NmpHdr, bincode::Error> {
-        let op = num::FromPrimitive::from_u8(cursor.read_u8()?).unwrap();
+    pub fn deserialize(cursor: &mut Cursor<&Vec<u8>>) -> Result<NmpHdr, Error> {
+        let op_byte = cursor.read_u8()?;
+        let Some(op) = num::FromPrimitive::from_u8(op_byte & 0x07) else {
+            let msg = format!("invalid NMP operation {}", op_byte & 0x07);
+            return Err(McuMgrError::Framing(msg).into());
+        };
+        let version = (op_byte >> 3) & 0x03;
         let flags = cursor.read_u8()?;
         let len = cursor.read_u16::<BigEndian>()?;
-        let group = num::FromPrimitive::from_u16(cursor.read_u16::<BigEndian>()?).unwrap();
+        let group = NmpGroup::from_u16(cursor.read_u16::<BigEndian>()?);
         let seq = cursor.read_u8()?;
         let id = cursor.read_u8()?;
         Ok(NmpHdr {
             op,
+            version,
             flags,
             len,
             group,
@@ -191,6 +334,7 @@ impl NmpHdr {
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[allow(dead_code)]
 pub struct NmpBase {
     pub hdr: NmpHdr,
 }
@@ -214,11 +358,12 @@ fn default_vec() -> Vec<u8> {
     Vec::new()
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ImageStateEntry {
     #[serde(default = "default_0")]
     pub image: u32,
     pub slot: u32,
+    #[serde(default)]
     pub version: String,
     #[serde(default = "default_vec", with = "HexForm")]
     pub hash: Vec<u8>,
@@ -234,10 +379,16 @@ pub struct ImageStateEntry {
     pub permanent: bool,
 }
 
+impl ImageStateEntry {
+    /// parsed `version`, for comparing against the version of an image file
+    pub fn image_version(&self) -> anyhow::Result<ImageVersion> {
+        self.version.parse()
+    }
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ImageStateReq {
-    #[serde(with = "serde_bytes")]
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
     pub hash: Vec<u8>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub confirm: Option<bool>,
@@ -250,6 +401,58 @@ pub struct ImageStateRsp {
     pub split_status: Option<SplitStatus>,
 }
 
+impl ImageStateRsp {
+    /// whether the same image is in more than one slot
+    pub fn has_duplicate_hashes(&self) -> bool {
+        self.images.iter().enumerate().any(|(i, img)| {
+            !img.hash.is_empty()
+                && self.images[i + 1..]
+                    .iter()
+                    .any(|other| other.hash == img.hash)
+        })
+    }
+
+    /// Slots which differ between this state and `other`, matched by image
+    /// and slot number. Entries of `self` are the old ones.
+    pub fn diff(&self, other: &ImageStateRsp) -> StateDiff {
+        let find = |images: &[ImageStateEntry], entry: &ImageStateEntry| {
+            images
+                .iter()
+                .find(|e| e.image == entry.image && e.slot == entry.slot)
+                .cloned()
+        };
+        let mut diff = StateDiff::default();
+        for old in self.images.iter() {
+            match find(&other.images, old) {
+                Some(new) if new != *old => diff.changed.push((old.clone(), new)),
+                Some(_) => (),
+                None => diff.removed.push(old.clone()),
+            }
+        }
+        for new in other.images.iter() {
+            if find(&self.images, new).is_none() {
+                diff.added.push(new.clone());
+            }
+        }
+        diff
+    }
+}
+
+/// slots changed between two image states, see `ImageStateRsp::diff`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct StateDiff {
+    pub added: Vec<ImageStateEntry>,
+    pub removed: Vec<ImageStateEntry>,
+    /// old and new entry of each changed slot
+    pub changed: Vec<(ImageStateEntry, ImageStateEntry)>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ImageUploadReq {
     #[serde(rename = "data", with = "serde_bytes")]
@@ -257,9 +460,9 @@ pub struct ImageUploadReq {
     #[serde(rename = "image")]
     pub image_num: u8,
     #[serde(rename = "len", skip_serializing_if = "Option::is_none")]
-    pub len: Option<u32>,
+    pub len: Option<u64>,
     #[serde(rename = "off", default)]
-    pub off: u32,
+    pub off: u64,
     #[serde(
         rename = "sha",
         default,
@@ -271,8 +474,216 @@ pub struct ImageUploadReq {
     pub upgrade: Option<bool>,
 }
 
+/// SMP version 2 error, reported as `err` instead of a top level `rc`
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct SmpErr {
+    pub group: u16,
+    pub rc: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageUploadRsp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub err: Option<SmpErr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub off: Option<u64>,
+    #[serde(rename = "match", default, skip_serializing_if = "Option::is_none")]
+    pub data_match: Option<bool>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ImageEraseReq {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub slot: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SlotInfoSlot {
+    pub slot: u32,
+    pub size: u64,
+    /// image number to upload to this slot, if it can be uploaded to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_image_id: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SlotInfoImage {
+    pub image: u32,
+    pub slots: Vec<SlotInfoSlot>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_image_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SlotInfoRsp {
+    pub images: Vec<SlotInfoImage>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct McumgrParamsRsp {
+    pub buf_size: u32,
+    pub buf_count: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AppInfoReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AppInfoRsp {
+    pub output: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(slot: u32, hash: Vec<u8>, active: bool, confirmed: bool) -> ImageStateEntry {
+        ImageStateEntry {
+            image: 0,
+            slot,
+            version: "1.0.0".to_string(),
+            hash,
+            bootable: true,
+            pending: false,
+            confirmed,
+            active,
+            permanent: false,
+        }
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let mut hdr = NmpHdr::new_req(NmpOp::Write, NmpGroup::Image, NmpIdImage::Upload);
+        hdr.len = 0x1234;
+        hdr.seq = 7;
+        let bytes = hdr.serialize().unwrap();
+        assert_eq!(bytes, [2, 0, 0x12, 0x34, 0, 1, 7, 1]);
+
+        let parsed = NmpHdr::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(parsed.payload_len(), 0x1234);
+
+        assert_eq!(bytes.len(), NmpHdr::LEN);
+        assert_eq!(bytes[NmpHdr::OP_OFFSET], NmpOp::Write as u8);
+        assert_eq!(
+            bytes[NmpHdr::GROUP_OFFSET + 1],
+            NmpGroup::Image.to_u16() as u8
+        );
+        assert_eq!(bytes[NmpHdr::SEQ_OFFSET], 7);
+        assert_eq!(bytes[NmpHdr::ID_OFFSET], NmpIdImage::Upload as u8);
+        assert_eq!(NmpHdr::body_len(&bytes), Some(0x1234));
+        assert_eq!(NmpHdr::body_len(&bytes[..4]), None);
+    }
+
+    #[test]
+    fn test_invalid_op() {
+        let bytes = vec![5, 0, 0, 0, 0, 1, 0, 0];
+        let e = NmpHdr::deserialize(&mut Cursor::new(&bytes)).unwrap_err();
+        assert_eq!(e.to_string(), "invalid NMP operation 5");
+        assert!(matches!(e.downcast_ref(), Some(McuMgrError::Framing(_))));
+    }
+
+    #[test]
+    fn test_header_version() {
+        let mut hdr = NmpHdr::new_req(NmpOp::Read, NmpGroup::Default, NmpIdDef::McumgrParams);
+        hdr.version = 1;
+        let bytes = hdr.serialize().unwrap();
+        assert_eq!(bytes[NmpHdr::OP_OFFSET], 0x08);
+
+        let parsed = NmpHdr::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(parsed.op, NmpOp::Read);
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.smp_version(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_hashes() {
+        let mut state = ImageStateRsp {
+            images: vec![
+                entry(0, vec![1; 32], true, true),
+                entry(1, vec![2; 32], false, false),
+            ],
+            split_status: None,
+        };
+        assert!(!state.has_duplicate_hashes());
+        state.images[1].hash = vec![1; 32];
+        assert!(state.has_duplicate_hashes());
+
+        // slots without a hash are not duplicates
+        state.images[0].hash.clear();
+        state.images[1].hash.clear();
+        assert!(!state.has_duplicate_hashes());
+    }
+
+    #[test]
+    fn test_state_diff() {
+        let old = ImageStateRsp {
+            images: vec![
+                entry(0, vec![1; 32], true, false),
+                entry(1, vec![2; 32], false, false),
+            ],
+            split_status: None,
+        };
+
+        // stored as JSON, with the hashes in hex
+        let json = serde_json::to_string(&old).unwrap();
+        assert!(json.contains(&hex::encode([1; 32])));
+        let stored: ImageStateRsp = serde_json::from_str(&json).unwrap();
+        assert!(old.diff(&stored).is_empty());
+
+        let mut new = stored.clone();
+        new.images[0].confirmed = true;
+        new.images.remove(1);
+        new.images.push(entry(2, vec![3; 32], false, false));
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.changed,
+            vec![(old.images[0].clone(), new.images[0].clone())]
+        );
+        assert_eq!(diff.removed, vec![old.images[1].clone()]);
+        assert_eq!(diff.added, vec![new.images[1].clone()]);
+    }
+
+    #[test]
+    fn test_custom_group() {
+        let hdr = NmpHdr::new_req(NmpOp::Read, NmpGroup::Custom(0x1234), 5u8);
+        let bytes = hdr.serialize().unwrap();
+        assert_eq!(
+            bytes[NmpHdr::GROUP_OFFSET..NmpHdr::GROUP_OFFSET + 2],
+            [0x12, 0x34]
+        );
+        let parsed = NmpHdr::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(parsed.group, NmpGroup::Custom(0x1234));
+
+        assert_eq!(NmpGroup::from_u16(1), NmpGroup::Image);
+        assert_eq!(NmpGroup::Custom(1), NmpGroup::Image);
+        assert_ne!(NmpGroup::Custom(65), NmpGroup::PerUser);
+
+        assert_eq!(u16::from(NmpGroup::PerUser), 64);
+        assert_eq!(NmpGroup::from(0x1234u16), NmpGroup::Custom(0x1234));
+    }
+
+    #[test]
+    fn test_header_matches() {
+        let mut req = NmpHdr::new_req(NmpOp::Read, NmpGroup::Image, NmpIdImage::State);
+        req.seq = 3;
+        let mut rsp = req;
+        rsp.op = NmpOp::ReadRsp;
+        assert!(rsp.header_matches(&req));
+
+        rsp.seq = 4;
+        assert!(!rsp.header_matches(&req));
+        rsp.seq = 3;
+        rsp.group = NmpGroup::Default;
+        assert!(!rsp.header_matches(&req));
+        rsp.group = NmpGroup::Image;
+        rsp.op = NmpOp::WriteRsp;
+        assert!(!rsp.header_matches(&req));
+    }
 }
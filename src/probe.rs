@@ -0,0 +1,320 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use log::{debug, info};
+use serde::Serialize;
+use serde_cbor::Value;
+use serialport::SerialPort;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::default::{app_info, mcumgr_params, smp_version};
+use crate::image::{in_recovery, list, list_on_port};
+use crate::nmp_hdr::*;
+use crate::protocol::{check_rc_version, McuMgrError};
+use crate::transfer::{open_port, transceive_with, RetryPolicy, SerialSpecs};
+
+// a group that is not compiled in may not answer at all, don't wait long for it
+const CAPABILITY_TIMEOUT_S: u32 = 2;
+
+/// summary of what a device supports, each query is `None` if it failed
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceProbe {
+    pub mtu: usize,
+    pub smp_version: Option<u8>,
+    pub params: Option<McumgrParamsRsp>,
+    pub app_info: Option<String>,
+    pub images: Option<ImageStateRsp>,
+}
+
+// log why a query was skipped and drop the error
+fn optional<T>(what: &str, result: Result<T, Error>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            debug!("{} not available: {}", what, e);
+            None
+        }
+    }
+}
+
+pub fn probe(specs: &SerialSpecs) -> Result<DeviceProbe, Error> {
+    info!("probe device");
+
+    let probe = DeviceProbe {
+        mtu: specs.mtu,
+        smp_version: optional("SMP version", smp_version(specs)),
+        params: optional("mcumgr parameters", mcumgr_params(specs)),
+        app_info: optional("application info", app_info(specs, Some("a"))),
+        images: optional("image list", list(specs)),
+    };
+    if probe.smp_version.is_none()
+        && probe.params.is_none()
+        && probe.app_info.is_none()
+        && probe.images.is_none()
+    {
+        bail!("device did not answer any probe request");
+    }
+
+    Ok(probe)
+}
+
+impl fmt::Display for DeviceProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_unknown = |value: Option<String>| value.unwrap_or("unknown".to_string());
+        let rows = [
+            ("mtu", self.mtu.to_string()),
+            (
+                "smp",
+                or_unknown(self.smp_version.map(|v| format!("v{}", v))),
+            ),
+            (
+                "params",
+                or_unknown(
+                    self.params
+                        .as_ref()
+                        .map(|p| format!("{} buffers of {} bytes", p.buf_count, p.buf_size)),
+                ),
+            ),
+            ("app", or_unknown(self.app_info.clone())),
+            (
+                "images",
+                or_unknown(self.images.as_ref().map(|s| s.images.len().to_string())),
+            ),
+        ];
+        for (name, value) in rows {
+            writeln!(f, "{:<8}{}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// which groups of commands the device handles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Capabilities {
+    pub image: bool,
+    pub fs: bool,
+    pub echo: bool,
+    pub stats: bool,
+    pub shell: bool,
+    pub logs: bool,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = [
+            ("image", self.image),
+            ("fs", self.fs),
+            ("echo", self.echo),
+            ("stats", self.stats),
+            ("shell", self.shell),
+            ("logs", self.logs),
+        ];
+        for (name, supported) in rows {
+            writeln!(f, "{:<8}{}", name, if supported { "yes" } else { "no" })?;
+        }
+        Ok(())
+    }
+}
+
+impl Capabilities {
+    // groups MCUboot serial recovery does not implement
+    fn has_application_groups(&self) -> bool {
+        self.fs || self.stats || self.shell || self.logs
+    }
+}
+
+// Whether the device answers the request with something else than "not
+// supported". Errors like a missing argument still show the group is there.
+fn supports<F>(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    build: F,
+) -> bool
+where
+    F: FnOnce(&mut BTreeMap<Value, Value>),
+{
+    let policy = RetryPolicy {
+        max_retries: 0,
+        ..RetryPolicy::from(specs)
+    };
+    let result = transceive_with(port, specs, op, group, id, build, &policy)
+        .and_then(|(header, body)| check_rc_version(&body, Some(header.smp_version())));
+    match result {
+        Ok(()) => true,
+        Err(e) => match e.downcast_ref() {
+            Some(McuMgrError::DeviceError { rc, .. }) => *rc != NmpErr::ENotSup as i64,
+            _ => {
+                debug!("no answer from group {:?}: {}", group, e);
+                false
+            }
+        },
+    }
+}
+
+/// Send a harmless request to each group, to find which ones the device
+/// supports. Groups that don't answer within a few seconds are reported as
+/// not supported.
+pub fn capabilities(specs: &SerialSpecs) -> Result<Capabilities, Error> {
+    info!("probe device capabilities");
+
+    let specs = &SerialSpecs {
+        initial_timeout_s: specs.initial_timeout_s.min(CAPABILITY_TIMEOUT_S),
+        ..specs.clone()
+    };
+    let mut port = open_port(specs)?;
+    Ok(capabilities_on_port(&mut *port, specs))
+}
+
+fn capabilities_on_port(port: &mut dyn SerialPort, specs: &SerialSpecs) -> Capabilities {
+    let text = |s: &str| Value::Text(s.to_string());
+
+    Capabilities {
+        image: supports(
+            &mut *port,
+            specs,
+            NmpOp::Read,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            |_| (),
+        ),
+        fs: supports(
+            &mut *port,
+            specs,
+            NmpOp::Read,
+            NmpGroup::Fs,
+            NmpIdFs::File as u8,
+            |req| {
+                req.insert(text("name"), text(""));
+                req.insert(text("off"), Value::Integer(0));
+            },
+        ),
+        echo: supports(
+            &mut *port,
+            specs,
+            NmpOp::Write,
+            NmpGroup::Default,
+            NmpIdDef::Echo,
+            |req| {
+                req.insert(text("d"), text("ping"));
+            },
+        ),
+        stats: supports(
+            &mut *port,
+            specs,
+            NmpOp::Read,
+            NmpGroup::Stat,
+            NmpIdStat::List as u8,
+            |_| (),
+        ),
+        // an empty command line is rejected without running anything
+        shell: supports(
+            &mut *port,
+            specs,
+            NmpOp::Write,
+            NmpGroup::Shell,
+            NmpIdShell::Exec as u8,
+            |req| {
+                req.insert(text("argv"), Value::Array(Vec::new()));
+            },
+        ),
+        logs: supports(
+            &mut *port,
+            specs,
+            NmpOp::Read,
+            NmpGroup::Log,
+            NmpIdLog::ModuleList as u8,
+            |_| (),
+        ),
+    }
+}
+
+/// which firmware answers the SMP requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DeviceContext {
+    /// MCUboot serial recovery, only image upload, list and reset work
+    Bootloader,
+    Application,
+}
+
+/// Whether the device runs MCUboot serial recovery or the application. A
+/// running image means the application. Without one, the device may still
+/// be an application with nothing marked active, so a group only the
+/// application has decides it.
+pub fn detect_context(specs: &SerialSpecs) -> Result<DeviceContext, Error> {
+    let mut port = open_port(specs)?;
+    let state = list_on_port(&mut *port, specs)
+        .map_err(|e| e.context("device does not answer image requests"))?;
+    let context = if !in_recovery(&state) {
+        DeviceContext::Application
+    } else {
+        port.set_timeout(Duration::from_secs(CAPABILITY_TIMEOUT_S as u64))?;
+        if capabilities_on_port(&mut *port, specs).has_application_groups() {
+            DeviceContext::Application
+        } else {
+            DeviceContext::Bootloader
+        }
+    };
+    debug!("device context: {:?}", context);
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_serial_port::test_specs;
+
+    #[test]
+    fn test_probe_degrades() {
+        let specs = test_specs();
+        let probe = probe(&specs).unwrap();
+        assert_eq!(probe.mtu, specs.mtu);
+        assert!(probe.smp_version.is_none());
+        assert!(probe.params.is_none());
+        assert!(probe.app_info.is_none());
+        assert_eq!(probe.images.as_ref().unwrap().images.len(), 1);
+        assert_eq!(
+            probe.to_string(),
+            format!(
+                "mtu     {}\nsmp     unknown\nparams  unknown\napp     unknown\nimages  1\n",
+                specs.mtu
+            )
+        );
+
+        let probe = DeviceProbe {
+            smp_version: Some(2),
+            params: Some(McumgrParamsRsp {
+                buf_size: 2475,
+                buf_count: 4,
+            }),
+            ..probe
+        };
+        let table = probe.to_string();
+        assert!(table.contains("\nsmp     v2\nparams  4 buffers of 2475 bytes\n"));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let specs = test_specs();
+        let capabilities = capabilities(&specs).unwrap();
+        assert_eq!(
+            capabilities,
+            Capabilities {
+                image: true,
+                ..Default::default()
+            }
+        );
+        let table = capabilities.to_string();
+        assert!(table.starts_with("image   yes\nfs      no\n"));
+    }
+
+    #[test]
+    fn test_detect_context() {
+        let specs = test_specs();
+        assert_eq!(detect_context(&specs).unwrap(), DeviceContext::Application);
+    }
+}
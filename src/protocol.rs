@@ -0,0 +1,649 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+// Transport independent part of the protocol: NMP packets and their framing
+// on the SMP console. It needs none of the serial port dependencies, so it
+// builds without the default "serial" feature.
+
+use anyhow::{bail, Error, Result};
+use base64::{engine::general_purpose, Engine as _};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use crc16::*;
+use log::{debug, warn};
+use std::cmp::min;
+use std::fmt;
+use std::io::Cursor;
+
+use crate::nmp_hdr::*;
+
+/// start of a frame on the SMP console
+pub const START_MARKER: [u8; 2] = [6, 9];
+
+/// start of each continuation line of a frame on the SMP console
+pub const CONTINUATION_MARKER: [u8; 2] = [4, 20];
+
+/// shortest line that fits the marker, some base64 data and the newline
+pub const MIN_LINELENGTH: usize = 8;
+
+/// Error for a response frame too short to hold an NMP header
+#[derive(Debug)]
+pub struct MalformedResponse {
+    pub got: usize,
+    pub expected_min: usize,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for MalformedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed response, got {} bytes, expected at least {}: {}",
+            self.got,
+            self.expected_min,
+            hex::encode(&self.data)
+        )
+    }
+}
+
+impl std::error::Error for MalformedResponse {}
+
+/// Kind of failure of a command, downcast the returned `anyhow::Error` to
+/// this to handle them differently, e.g. to retry on a timeout
+#[derive(Debug)]
+pub enum McuMgrError {
+    /// the device answered with a non zero result code
+    DeviceError { group: Option<u16>, rc: i64 },
+    /// no answer from the device in time
+    Timeout,
+    /// the response frame has a wrong checksum
+    Crc,
+    /// the response frame is not a valid NMP packet
+    Framing(String),
+    /// no chunk of the upload fits in the MTU
+    TooLargeChunk,
+    /// the port or stream failed
+    Transport(std::io::Error),
+    /// the port went away, e.g. the USB serial adapter was unplugged
+    DeviceDisconnected(std::io::Error),
+}
+
+impl fmt::Display for McuMgrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            McuMgrError::DeviceError { group: None, rc } => write!(f, "rc = {}", rc),
+            McuMgrError::DeviceError {
+                group: Some(group),
+                rc,
+            } => write!(f, "group = {}, rc = {}", group, rc),
+            McuMgrError::Timeout => write!(f, "timeout waiting for the device"),
+            McuMgrError::Crc => write!(f, "wrong checksum"),
+            McuMgrError::Framing(msg) => write!(f, "{}", msg),
+            McuMgrError::TooLargeChunk => write!(f, "MTU too small"),
+            McuMgrError::Transport(e) => write!(f, "{}", e),
+            McuMgrError::DeviceDisconnected(e) => write!(f, "device disconnected ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for McuMgrError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            McuMgrError::Transport(e) | McuMgrError::DeviceDisconnected(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// whether the error means the port is gone, rather than a failed transfer
+fn is_disconnect(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    if matches!(
+        e.kind(),
+        BrokenPipe | NotConnected | ConnectionReset | ConnectionAborted
+    ) {
+        return true;
+    }
+    // ENXIO and ENODEV on Linux and macOS. EIO and access denied are left
+    // out, they also come from a device that is still there.
+    #[cfg(unix)]
+    let codes = [6, 19];
+    // ERROR_GEN_FAILURE and ERROR_DEVICE_NOT_CONNECTED
+    #[cfg(windows)]
+    let codes = [31, 1167];
+    #[cfg(not(any(unix, windows)))]
+    let codes: [i32; 0] = [];
+    matches!(e.raw_os_error(), Some(code) if codes.contains(&code))
+}
+
+impl From<std::io::Error> for McuMgrError {
+    fn from(e: std::io::Error) -> McuMgrError {
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            McuMgrError::Timeout
+        } else if is_disconnect(&e) {
+            McuMgrError::DeviceDisconnected(e)
+        } else {
+            McuMgrError::Transport(e)
+        }
+    }
+}
+
+/// CBOR encode a request body. With `canonical`, map keys are sorted as
+/// canonical CBOR (RFC 7049 section 3.9): shorter keys first, then bytewise,
+/// instead of in the order of the struct fields.
+pub fn encode_body(req: &impl serde::Serialize, canonical: bool) -> Result<Vec<u8>, Error> {
+    if canonical {
+        // maps of a `Value` are kept in canonical order
+        let value = serde_cbor::value::to_value(req)?;
+        Ok(serde_cbor::to_vec(&value)?)
+    } else {
+        Ok(serde_cbor::to_vec(req)?)
+    }
+}
+
+/// Build an NMP packet: the header followed by the CBOR encoded body
+pub fn encode_packet(
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    body: &[u8],
+    seq_id: u8,
+) -> Result<(Vec<u8>, NmpHdr), Error> {
+    // create request
+    let mut request_header = NmpHdr::new_req(op, group, id);
+    request_header.seq = seq_id;
+    request_header.len = body.len() as u16;
+    debug!("request header: {:?}", request_header);
+    let mut serialized = request_header.serialize()?;
+    serialized.extend(body);
+    debug!("serialized: {}", hex::encode(&serialized));
+
+    Ok((serialized, request_header))
+}
+
+/// Frame an NMP packet for the SMP console: append the CRC, prepend the
+/// length, encode in base64 and split into lines of at most `linelength`
+/// bytes, each starting with the start or continuation marker.
+pub fn encode_frame(
+    packet: &[u8],
+    linelength: usize,
+    start_marker: [u8; 2],
+    continuation_marker: [u8; 2],
+) -> Result<Vec<u8>, Error> {
+    if linelength < MIN_LINELENGTH {
+        bail!("linelength must be >= {}", MIN_LINELENGTH);
+    }
+    let mut serialized = packet.to_vec();
+
+    // calculate CRC16 of it and append to the request
+    let checksum = State::<XMODEM>::calculate(&serialized);
+    serialized.write_u16::<BigEndian>(checksum)?;
+
+    // prepend chunk length
+    let mut len: Vec<u8> = Vec::new();
+    len.write_u16::<BigEndian>(serialized.len() as u16)?;
+    serialized.splice(0..0, len);
+    debug!(
+        "encoded with packet length and checksum: {}",
+        hex::encode(&serialized)
+    );
+
+    // convert to base64
+    let base64_data: Vec<u8> = general_purpose::STANDARD.encode(&serialized).into_bytes();
+    debug!("encoded: {}", String::from_utf8_lossy(&base64_data));
+    let mut data = Vec::<u8>::new();
+
+    // transfer in blocks of max linelength bytes per line
+    let mut written = 0;
+    let totlen = base64_data.len();
+    while written < totlen {
+        // start designator
+        if written == 0 {
+            data.extend_from_slice(&start_marker);
+        } else {
+            // TODO: add a configurable sleep for slower devices
+            // thread::sleep(Duration::from_millis(20));
+            data.extend_from_slice(&continuation_marker);
+        }
+        let write_len = min(linelength - 4, totlen - written);
+        data.extend_from_slice(&base64_data[written..written + write_len]);
+        data.push(b'\n');
+        written += write_len;
+    }
+
+    Ok(data)
+}
+
+pub fn get_rc(response_body: &serde_cbor::Value) -> Option<u32> {
+    let mut rc: Option<u32> = None;
+    if let serde_cbor::Value::Map(object) = response_body {
+        for (key, val) in object.iter() {
+            match key {
+                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
+                    if let serde_cbor::Value::Integer(parsed_rc) = val {
+                        rc = Some(*parsed_rc as u32);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    rc
+}
+
+/// group and rc of an SMP v2 `err` map, if the response has one
+pub fn get_err(response_body: &serde_cbor::Value) -> Option<SmpErr> {
+    let serde_cbor::Value::Map(object) = response_body else {
+        return None;
+    };
+    let err = object.get(&serde_cbor::Value::Text("err".to_string()))?;
+    serde_cbor::value::from_value(err.clone()).ok()
+}
+
+/// fail with `McuMgrError::DeviceError` if the response has a non zero rc,
+/// either the SMP v1 `rc` or in the SMP v2 `err` map
+pub fn check_rc(response_body: &serde_cbor::Value) -> Result<(), Error> {
+    check_rc_version(response_body, None)
+}
+
+/// `check_rc` for a device with a known SMP version: `err` is only looked
+/// at for SMP v2, and before the `rc` still used for SMP level errors
+pub fn check_rc_version(
+    response_body: &serde_cbor::Value,
+    smp_version: Option<u8>,
+) -> Result<(), Error> {
+    if smp_version != Some(1) {
+        if let Some(err) = get_err(response_body) {
+            if err.rc != 0 {
+                return Err(McuMgrError::DeviceError {
+                    group: Some(err.group),
+                    rc: err.rc,
+                }
+                .into());
+            }
+        }
+    }
+    match get_rc(response_body) {
+        Some(rc) if rc != 0 => Err(McuMgrError::DeviceError {
+            group: None,
+            rc: rc as i64,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr) -> bool {
+    response_header.header_matches(request_header)
+}
+
+pub fn decode_serial_frame(decoded: &[u8]) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    // a frame must hold at least the length, the NMP header and the checksum
+    let expected_min = 2 + NMP_HDR_LEN + 2;
+    if decoded.len() < expected_min {
+        return Err(MalformedResponse {
+            got: decoded.len(),
+            expected_min,
+            data: decoded.to_vec(),
+        }
+        .into());
+    }
+
+    // verify length: must be the decoded length, minus the 2 bytes to encode the length
+    let len = BigEndian::read_u16(decoded) as usize;
+    if len != decoded.len() - 2 {
+        return Err(McuMgrError::Framing("wrong chunk length".to_string()).into());
+    }
+
+    // verify checksum
+    let data = decoded[2..decoded.len() - 2].to_vec();
+    let read_checksum = BigEndian::read_u16(&decoded[decoded.len() - 2..]);
+    let calculated_checksum = State::<XMODEM>::calculate(&data);
+    if read_checksum != calculated_checksum {
+        return Err(McuMgrError::Crc.into());
+    }
+
+    // read header
+    let mut cursor = Cursor::new(&data);
+    let response_header = NmpHdr::deserialize(&mut cursor)?;
+    debug!("response header: {:?}", response_header);
+    if response_header.payload_len() != data.len() - NMP_HDR_LEN {
+        return Err(McuMgrError::Framing(format!(
+            "wrong payload length, header: {}, received: {}",
+            response_header.payload_len(),
+            data.len() - NMP_HDR_LEN
+        ))
+        .into());
+    }
+
+    debug!("cbor: {}", hex::encode(&data[NMP_HDR_LEN..]));
+
+    // decode body in CBOR format
+    let body = match serde_cbor::from_slice(&data[NMP_HDR_LEN..]) {
+        Ok(body) => body,
+        Err(e) => decode_lossy(&data[NMP_HDR_LEN..], &e).ok_or(e)?,
+    };
+
+    Ok((response_header, body))
+}
+
+// Decode a body with text strings that are not valid UTF-8, e.g. an image
+// version from buggy firmware, by replacing the invalid bytes. `None` if the
+// body failed to decode for another reason.
+fn decode_lossy(body: &[u8], e: &serde_cbor::Error) -> Option<serde_cbor::Value> {
+    let mut repaired = repair_text(body, e.offset() as usize)?;
+    // each pass repairs one string, there are fewer strings than bytes
+    for _ in 0..body.len() {
+        match serde_cbor::from_slice(&repaired) {
+            Ok(value) => {
+                warn!("invalid UTF-8 in a response string, replaced the invalid bytes");
+                return Some(value);
+            }
+            Err(e) => repaired = repair_text(&repaired, e.offset() as usize)?,
+        }
+    }
+    None
+}
+
+// Convert the text string with the invalid UTF-8 at `offset` lossily. Its
+// head is the nearest one before `offset` for which the decoder then gets
+// past the string.
+fn repair_text(body: &[u8], offset: usize) -> Option<Vec<u8>> {
+    (0..offset.min(body.len())).rev().find_map(|head| {
+        if body[head] >> 5 != 3 {
+            return None;
+        }
+        // read it as a byte string, major type 2, to get its end
+        let mut item = body[head..].to_vec();
+        item[0] ^= 0x20;
+        let mut de = serde_cbor::Deserializer::from_slice(&item);
+        let text: serde_bytes::ByteBuf = serde::Deserialize::deserialize(&mut de).ok()?;
+        let end = head + de.byte_offset();
+        let valid = std::str::from_utf8(&text).err()?.valid_up_to();
+        if end - text.len() + valid != offset {
+            return None;
+        }
+
+        let lossy = serde_cbor::to_vec(&String::from_utf8_lossy(&text)).ok()?;
+        let repaired = [&body[..head], &lossy, &body[end..]].concat();
+        match serde_cbor::from_slice::<serde_cbor::Value>(&repaired) {
+            Err(e) if (e.offset() as usize) < head + lossy.len() => None,
+            _ => Some(repaired),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_cbor::Value;
+    use std::collections::BTreeMap;
+
+    // small deterministic generator, so failures can be reproduced
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    // strip the markers and newlines of a frame and decode the base64 data
+    fn unframe(frame: &[u8]) -> Vec<u8> {
+        let mut base64_data = Vec::new();
+        for line in frame.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            base64_data.extend_from_slice(&line[2..]);
+        }
+        general_purpose::STANDARD.decode(base64_data).unwrap()
+    }
+
+    fn random_frame(rng: &mut XorShift) -> (Vec<u8>, NmpHdr, Value) {
+        let mut map = BTreeMap::new();
+        let len = rng.below(600);
+        map.insert(
+            Value::Text("data".to_string()),
+            Value::Bytes(rng.bytes(len)),
+        );
+        map.insert(
+            Value::Text("off".to_string()),
+            Value::Integer(rng.next() as u32 as i128),
+        );
+        let body = Value::Map(map);
+        let (packet, header) = encode_packet(
+            NmpOp::Write,
+            NmpGroup::Image,
+            NmpIdImage::Upload,
+            &serde_cbor::to_vec(&body).unwrap(),
+            rng.next() as u8,
+        )
+        .unwrap();
+        let linelength = MIN_LINELENGTH + rng.below(200);
+        let frame = encode_frame(&packet, linelength, START_MARKER, CONTINUATION_MARKER).unwrap();
+        for line in frame.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            assert!(line.len() < linelength);
+        }
+        (frame, header, body)
+    }
+
+    #[test]
+    fn test_io_error_kind() {
+        let from = |e: std::io::Error| McuMgrError::from(e);
+        assert!(matches!(
+            from(std::io::ErrorKind::TimedOut.into()),
+            McuMgrError::Timeout
+        ));
+        assert!(matches!(
+            from(std::io::ErrorKind::BrokenPipe.into()),
+            McuMgrError::DeviceDisconnected(_)
+        ));
+        assert!(matches!(
+            from(std::io::ErrorKind::UnexpectedEof.into()),
+            McuMgrError::Transport(_)
+        ));
+        #[cfg(unix)]
+        {
+            let e = from(std::io::Error::from_raw_os_error(6));
+            assert!(matches!(e, McuMgrError::DeviceDisconnected(_)));
+            assert!(e.to_string().starts_with("device disconnected ("));
+            // EIO
+            let e = from(std::io::Error::from_raw_os_error(5));
+            assert!(matches!(e, McuMgrError::Transport(_)));
+        }
+    }
+
+    #[test]
+    fn test_canonical_body() {
+        let req = ImageUploadReq {
+            data: vec![1, 2, 3],
+            image_num: 0,
+            len: Some(3),
+            off: 0,
+            data_sha: None,
+            upgrade: None,
+        };
+        let key_order = |body: &[u8]| {
+            let mut keys: Vec<(usize, &str)> = ["data", "image", "len", "off"]
+                .iter()
+                .map(|key| {
+                    let pos = body.windows(key.len()).position(|w| w == key.as_bytes());
+                    (pos.unwrap(), *key)
+                })
+                .collect();
+            keys.sort();
+            keys.into_iter().map(|(_, key)| key).collect::<Vec<_>>()
+        };
+
+        let body = encode_body(&req, false).unwrap();
+        assert_eq!(key_order(&body), ["data", "image", "len", "off"]);
+        let canonical = encode_body(&req, true).unwrap();
+        assert_eq!(key_order(&canonical), ["len", "off", "data", "image"]);
+        assert_eq!(canonical.len(), body.len());
+        assert_eq!(
+            serde_cbor::from_slice::<Value>(&canonical).unwrap(),
+            serde_cbor::from_slice::<Value>(&body).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check_rc_version() {
+        let mut err = BTreeMap::new();
+        err.insert(Value::Text("group".to_string()), Value::Integer(1));
+        err.insert(Value::Text("rc".to_string()), Value::Integer(5));
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("err".to_string()), Value::Map(err));
+        let v2 = Value::Map(map);
+
+        let e = check_rc(&v2).unwrap_err();
+        assert_eq!(e.to_string(), "group = 1, rc = 5");
+        assert!(check_rc_version(&v2, Some(2)).is_err());
+        // an SMP v1 device has no err map
+        assert!(check_rc_version(&v2, Some(1)).is_ok());
+
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("rc".to_string()), Value::Integer(8));
+        let v1 = Value::Map(map);
+        assert_eq!(
+            check_rc_version(&v1, Some(2)).unwrap_err().to_string(),
+            "rc = 8"
+        );
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut rng = XorShift(0x5eed);
+        for _ in 0..200 {
+            let (frame, header, body) = random_frame(&mut rng);
+            assert_eq!(frame[..2], START_MARKER);
+            let (decoded_header, decoded_body) = decode_serial_frame(&unframe(&frame)).unwrap();
+            assert_eq!(decoded_header.seq, header.seq);
+            assert_eq!(decoded_header.len, header.len);
+            assert_eq!(decoded_body, body);
+        }
+    }
+
+    #[test]
+    fn test_indefinite_length_body() {
+        // {_ "entries": [_ 1, 2, 3]}, as streamed by some log and stat handlers
+        let body = [
+            0xbf, 0x67, b'e', b'n', b't', b'r', b'i', b'e', b's', 0x9f, 0x01, 0x02, 0x03, 0xff,
+            0xff,
+        ];
+        let (packet, _) = encode_packet(
+            NmpOp::ReadRsp,
+            NmpGroup::Log,
+            NmpIdLog::Show as u8,
+            &body,
+            1,
+        )
+        .unwrap();
+        // short lines, so the body spans several of them
+        let frame =
+            encode_frame(&packet, MIN_LINELENGTH, START_MARKER, CONTINUATION_MARKER).unwrap();
+        let (_, decoded) = decode_serial_frame(&unframe(&frame)).unwrap();
+
+        let mut map = BTreeMap::new();
+        map.insert(
+            Value::Text("entries".to_string()),
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+            ]),
+        );
+        assert_eq!(decoded, Value::Map(map));
+    }
+
+    #[test]
+    fn test_invalid_utf8_version() {
+        // {"images": [{"slot": 0, "version": "1.\xff"}]}
+        let mut body = vec![0xa1, 0x66];
+        body.extend_from_slice(b"images");
+        body.extend_from_slice(&[0x81, 0xa2, 0x64]);
+        body.extend_from_slice(b"slot");
+        body.extend_from_slice(&[0x00, 0x67]);
+        body.extend_from_slice(b"version");
+        body.extend_from_slice(&[0x63, b'1', b'.', 0xff]);
+        let (packet, _) =
+            encode_packet(NmpOp::ReadRsp, NmpGroup::Image, NmpIdImage::State, &body, 1).unwrap();
+        let frame = encode_frame(&packet, 128, START_MARKER, CONTINUATION_MARKER).unwrap();
+        let (_, decoded) = decode_serial_frame(&unframe(&frame)).unwrap();
+
+        let state: ImageStateRsp = serde_cbor::value::from_value(decoded).unwrap();
+        assert_eq!(state.images[0].version, "1.\u{fffd}");
+
+        // the 'a' before the invalid byte also reads as the head of a string
+        let body = [0x64, b'1', b'.', b'a', 0xff];
+        let e = serde_cbor::from_slice::<Value>(&body).unwrap_err();
+        let decoded = decode_lossy(&body, &e).unwrap();
+        assert_eq!(decoded, Value::Text("1.a\u{fffd}".to_string()));
+
+        // still an error if the body is broken otherwise
+        let (packet, _) = encode_packet(
+            NmpOp::ReadRsp,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            &body[..body.len() - 1],
+            1,
+        )
+        .unwrap();
+        let frame = encode_frame(&packet, 128, START_MARKER, CONTINUATION_MARKER).unwrap();
+        assert!(decode_serial_frame(&unframe(&frame)).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_frames() {
+        let mut rng = XorShift(0xbad);
+        for _ in 0..50 {
+            let (frame, _, _) = random_frame(&mut rng);
+            let decoded = unframe(&frame);
+
+            // truncated
+            let cut = rng.below(decoded.len());
+            assert!(decode_serial_frame(&decoded[..cut]).is_err());
+
+            // bad checksum, a changed byte is always caught by the CRC
+            let mut corrupted = decoded.clone();
+            let pos = 2 + rng.below(decoded.len() - 2);
+            corrupted[pos] ^= 1 + rng.below(255) as u8;
+            let e = decode_serial_frame(&corrupted).unwrap_err();
+            assert_eq!(e.to_string(), "wrong checksum");
+            assert!(matches!(e.downcast_ref(), Some(McuMgrError::Crc)));
+
+            // wrong length prefix
+            let mut corrupted = decoded.clone();
+            corrupted[1] = corrupted[1].wrapping_add(1 + rng.below(255) as u8);
+            let e = decode_serial_frame(&corrupted).unwrap_err();
+            assert_eq!(e.to_string(), "wrong chunk length");
+            assert!(matches!(e.downcast_ref(), Some(McuMgrError::Framing(_))));
+        }
+    }
+
+    #[test]
+    fn test_short_frame() {
+        // a frame with a 3 byte payload, too short for an NMP header
+        let payload = [1u8, 2, 3];
+        let mut frame = Vec::new();
+        frame
+            .write_u16::<BigEndian>(payload.len() as u16 + 2)
+            .unwrap();
+        frame.extend_from_slice(&payload);
+        frame
+            .write_u16::<BigEndian>(State::<XMODEM>::calculate(&payload))
+            .unwrap();
+
+        let err = decode_serial_frame(&frame).unwrap_err();
+        let malformed = err.downcast_ref::<MalformedResponse>().unwrap();
+        assert_eq!(malformed.got, 7);
+        assert_eq!(malformed.expected_min, 12);
+        assert_eq!(malformed.data, frame);
+    }
+}
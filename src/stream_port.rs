@@ -0,0 +1,341 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Context, Error, Result};
+use serialport::DataBits;
+use serialport::FlowControl;
+use serialport::Parity;
+use serialport::SerialPort;
+use serialport::StopBits;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Serial port over any byte stream, e.g. a pseudo-terminal, a socket or a
+/// pipe to a simulator, so the SMP console framing can be used on it with
+/// the `*_on_port` commands or `transceive_with_retry`. The line settings are only recorded. The timeout
+/// is reported to the caller but must be enforced by the stream itself, for
+/// example with `TcpStream::set_read_timeout`, and reads have to fail with
+/// `ErrorKind::TimedOut` or `ErrorKind::WouldBlock` for the retries to work.
+/// Only a TCP stream reports and discards pending input, for the others stale
+/// bytes are not flushed before a request.
+pub struct StreamPort<S: Read + Write + Send> {
+    stream: S,
+    name: Option<String>,
+    baud_rate: u32,
+    timeout: Duration,
+    apply_timeout: fn(&mut S, Duration) -> std::io::Result<()>,
+    pending: fn(&S) -> std::io::Result<u32>,
+    discard: fn(&S) -> std::io::Result<()>,
+}
+
+impl<S: Read + Write + Send> StreamPort<S> {
+    pub fn new(stream: S, timeout: Duration) -> StreamPort<S> {
+        StreamPort {
+            stream,
+            name: None,
+            baud_rate: 115_200,
+            timeout,
+            apply_timeout: |_, _| Ok(()),
+            pending: |_| Ok(0),
+            discard: |_| Ok(()),
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> StreamPort<S> {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl StreamPort<TcpStream> {
+    /// connect to a raw TCP socket forwarding the SMP console, e.g. ser2net
+    pub fn connect_tcp(addr: &str, timeout: Duration) -> Result<StreamPort<TcpStream>, Error> {
+        let stream =
+            TcpStream::connect(addr).with_context(|| format!("failed to connect to {}", addr))?;
+        stream.set_nodelay(true)?;
+        let mut port = StreamPort::new(stream, timeout).with_name(addr);
+        port.apply_timeout = |stream, timeout| {
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))
+        };
+        port.pending = |stream| {
+            let mut buf = [0; 1024];
+            let pending = nonblocking(stream, |stream| stream.peek(&mut buf))?;
+            Ok(pending as u32)
+        };
+        port.discard = |stream| {
+            let mut buf = [0; 1024];
+            nonblocking(stream, |mut stream| loop {
+                if stream.read(&mut buf)? == 0 {
+                    return Ok(0);
+                }
+            })?;
+            Ok(())
+        };
+        port.set_timeout(timeout)?;
+        Ok(port)
+    }
+}
+
+// Run `op` on the stream without blocking, nothing is available once it would
+// block. The socket timeouts are kept, they only apply in blocking mode.
+fn nonblocking<F>(stream: &TcpStream, op: F) -> std::io::Result<usize>
+where
+    F: FnOnce(&TcpStream) -> std::io::Result<usize>,
+{
+    stream.set_nonblocking(true)?;
+    let result = match op(stream) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+        other => other,
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+impl<S: Read + Write + Send> Read for StreamPort<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.stream.read(buf) {
+            // some platforms report an expired read timeout as WouldBlock
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                Err(std::io::Error::new(ErrorKind::TimedOut, e))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<S: Read + Write + Send> Write for StreamPort<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Read + Write + Send> SerialPort for StreamPort<S> {
+    fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: serialport::DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(
+        &mut self,
+        _flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: serialport::Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        (self.apply_timeout)(&mut self.stream, timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok((self.pending)(&self.stream)?)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        // written data is sent right away, there is no output buffer to clear
+        match buffer_to_clear {
+            serialport::ClearBuffer::Output => Ok(()),
+            _ => Ok((self.discard)(&self.stream)?),
+        }
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "a stream port can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default::reset_on_port;
+    use crate::image::{list, list_on_port, test_on_port, upload_on_port, Slot, UploadOptions};
+    use crate::nmp_hdr::*;
+    use crate::test_serial_port::TestSerialPort;
+    use crate::transfer::{transceive_with_retry, RetryPolicy, SerialSpecs};
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_stream_port() {
+        let specs = SerialSpecs::default();
+        let mut port = StreamPort::new(TestSerialPort::new(&specs), Duration::from_secs(1));
+        let (_, body) = transceive_with_retry(
+            &mut port,
+            &specs,
+            NmpOp::Read,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            &BTreeMap::<String, String>::new(),
+            &RetryPolicy::from(&specs),
+        )
+        .unwrap();
+        let state: ImageStateRsp = serde_cbor::value::from_value(body).unwrap();
+        assert_eq!(state.images.len(), 1);
+    }
+
+    #[test]
+    fn test_commands_on_stream() {
+        // the simulated device is an in-memory stream, answering each request
+        let specs = SerialSpecs::default();
+        let mut port = StreamPort::new(TestSerialPort::new(&specs), Duration::from_secs(1));
+        let state = list_on_port(&mut port, &specs).unwrap();
+        assert_eq!(state.images.len(), 1);
+
+        let options = UploadOptions::default();
+        let image = vec![0x5a; 1000];
+        let none = None::<fn(u64, u64)>;
+        let stats = upload_on_port(&mut port, &specs, &options, &image, Slot::Secondary, none);
+        assert_eq!(stats.unwrap().bytes, 1000);
+
+        test_on_port(&mut port, &specs, state.images[0].hash.clone(), None).unwrap();
+        reset_on_port(&mut port, &specs).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_device() {
+        // a bridge answering a single request with the simulated device
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bridge = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut line = Vec::new();
+            BufReader::new(&socket)
+                .read_until(b'\n', &mut line)
+                .unwrap();
+            let mut device = TestSerialPort::new(&SerialSpecs::default());
+            device.write_all(&line).unwrap();
+            let mut response = Vec::new();
+            device.read_to_end(&mut response).unwrap();
+            socket.write_all(&response).unwrap();
+        });
+
+        let specs = SerialSpecs {
+            device: format!("tcp://{}", addr),
+            ..Default::default()
+        };
+        let state = list(&specs).unwrap();
+        assert_eq!(state.images.len(), 1);
+        bridge.join().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_pending_input() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let console = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.write_all(b"uart:~$ \n").unwrap();
+            // keep the connection open until the client is done
+            let mut reader = BufReader::new(&socket);
+            reader.read_until(b'\n', &mut Vec::new()).unwrap();
+        });
+
+        let timeout = Duration::from_secs(1);
+        let mut port = StreamPort::connect_tcp(&addr.to_string(), timeout).unwrap();
+        let start = Instant::now();
+        while port.bytes_to_read().unwrap() == 0 && start.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(port.bytes_to_read().unwrap(), 9);
+        port.clear(serialport::ClearBuffer::Input).unwrap();
+        assert_eq!(port.bytes_to_read().unwrap(), 0);
+
+        // still blocking, with the timeout
+        let e = port.read(&mut [0]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        port.write_all(b"\n").unwrap();
+        console.join().unwrap();
+    }
+}
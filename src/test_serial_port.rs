@@ -4,7 +4,6 @@ use base64::engine::{general_purpose::STANDARD, Engine};
 use byteorder::{BigEndian, ByteOrder};
 use crc16::State;
 use crc16::XMODEM;
-use hex;
 use serialport::DataBits;
 use serialport::FlowControl;
 use serialport::Parity;
@@ -17,17 +16,20 @@ use std::time::Duration;
 
 use crate::nmp_hdr::*;
 use crate::transfer::encode_request;
+use crate::transfer::SerialSpecs;
 
 pub struct TestSerialPort {
+    specs: SerialSpecs,
     data: Vec<u8>,
     position: usize,
-    total_len: u32,
+    total_len: u64,
     images: Vec<ImageStateEntry>,
 }
 
 impl TestSerialPort {
-    pub fn new() -> TestSerialPort {
+    pub fn new(specs: &SerialSpecs) -> TestSerialPort {
         TestSerialPort {
+            specs: specs.clone(),
             data: Vec::new(),
             position: 0,
             total_len: 0,
@@ -61,6 +63,11 @@ impl Read for TestSerialPort {
 
 impl Write for TestSerialPort {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // a bare newline, as sent by resync, carries no request
+        if buf.iter().all(|&b| b == b'\n') {
+            return Ok(buf.len());
+        }
+
         let mut cursor = Cursor::new(buf);
         let mut received_data = Vec::new();
 
@@ -93,8 +100,8 @@ impl Write for TestSerialPort {
         // let header_len: usize = 8;
         // let request_body = data[header_len..].to_vec();
 
-        match request_header.id {
-            id if id == NmpIdImage::State as u8 => {
+        match (request_header.group, request_header.id) {
+            (NmpGroup::Image, id) if id == NmpIdImage::State as u8 => {
                 if request_header.op == NmpOp::Read {
                     let state_response = ImageStateRsp {
                         images: self.images.clone(),
@@ -102,7 +109,7 @@ impl Write for TestSerialPort {
                     };
                     let body = serde_cbor::to_vec(&state_response).unwrap();
                     let (encoded_response, _) = encode_request(
-                        100,
+                        &self.specs,
                         NmpOp::ReadRsp,
                         NmpGroup::Image,
                         NmpIdImage::State,
@@ -112,10 +119,32 @@ impl Write for TestSerialPort {
                     .unwrap();
                     self.data.extend_from_slice(&encoded_response);
                 } else if request_header.op == NmpOp::Write {
-                    // let request: ImageStateReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
-                    let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
+                    let body_start = request_cursor.position() as usize;
+                    let request: ImageStateReq =
+                        serde_cbor::from_slice(&data[body_start..]).unwrap();
+                    let confirm = request.confirm.unwrap_or(false);
+                    for image in self.images.iter_mut() {
+                        if request.hash.is_empty() {
+                            // no hash confirms the running image
+                            if confirm && image.active {
+                                image.confirmed = true;
+                            }
+                        } else if image.hash == request.hash {
+                            if confirm && image.active {
+                                image.confirmed = true;
+                            } else {
+                                image.pending = true;
+                                image.permanent = confirm;
+                            }
+                        }
+                    }
+                    let state_response = ImageStateRsp {
+                        images: self.images.clone(),
+                        split_status: None,
+                    };
+                    let body = serde_cbor::to_vec(&state_response).unwrap();
                     let (encoded_response, _) = encode_request(
-                        100,
+                        &self.specs,
                         NmpOp::WriteRsp,
                         NmpGroup::Image,
                         NmpIdImage::Erase,
@@ -126,7 +155,7 @@ impl Write for TestSerialPort {
                     self.data.extend_from_slice(&encoded_response);
                 }
             }
-            id if id == NmpIdImage::Upload as u8 => {
+            (NmpGroup::Image, id) if id == NmpIdImage::Upload as u8 => {
                 let body_start = request_cursor.position() as usize;
                 let body_end = data.len();
                 let body = &data[body_start..body_end];
@@ -135,7 +164,7 @@ impl Write for TestSerialPort {
                 if image_upload_req.off == 0 {
                     self.total_len = image_upload_req.len.unwrap();
                 }
-                let mut off_value = image_upload_req.off + data.len() as u32;
+                let mut off_value = image_upload_req.off + image_upload_req.data.len() as u64;
                 if off_value > self.total_len {
                     off_value = self.total_len;
                 }
@@ -146,7 +175,7 @@ impl Write for TestSerialPort {
 
                 let cbor_body = serde_cbor::to_vec(&response_map).unwrap();
                 let (encoded_response, _) = encode_request(
-                    4096,
+                    &self.specs,
                     NmpOp::WriteRsp,
                     NmpGroup::Image,
                     NmpIdImage::State,
@@ -156,11 +185,11 @@ impl Write for TestSerialPort {
                 .unwrap();
                 self.data.extend_from_slice(&encoded_response);
             }
-            id if id == NmpIdImage::Erase as u8 => {
+            (NmpGroup::Image, id) if id == NmpIdImage::Erase as u8 => {
                 // let request: ImageEraseReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
                 let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
                 let (encoded_response, _) = encode_request(
-                    100,
+                    &self.specs,
                     NmpOp::WriteRsp,
                     NmpGroup::Image,
                     NmpIdImage::Erase,
@@ -170,6 +199,75 @@ impl Write for TestSerialPort {
                 .unwrap();
                 self.data.extend_from_slice(&encoded_response);
             }
+            (NmpGroup::Image, id) if id == NmpIdImage::SlotInfo as u8 => {
+                let slot = |slot: u32, upload_image_id: Option<u32>| SlotInfoSlot {
+                    slot,
+                    size: 0x10000,
+                    upload_image_id,
+                };
+                let response = SlotInfoRsp {
+                    images: vec![SlotInfoImage {
+                        image: 0,
+                        slots: vec![slot(0, None), slot(1, Some(1))],
+                        max_image_size: None,
+                    }],
+                };
+                let body = serde_cbor::to_vec(&response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    &self.specs,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Image,
+                    NmpIdImage::SlotInfo,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            (NmpGroup::Default, id) if id == NmpIdDef::Reset as u8 => {
+                let mut response_map = std::collections::BTreeMap::new();
+                response_map.insert("rc", 0);
+
+                let body = serde_cbor::to_vec(&response_map).unwrap();
+                let (encoded_response, _) = encode_request(
+                    &self.specs,
+                    NmpOp::WriteRsp,
+                    NmpGroup::Default,
+                    NmpIdDef::Reset,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            #[cfg(feature = "mem-read")]
+            (NmpGroup::PerUser, 0) => {
+                // memory read, answering with the low byte of each address,
+                // and an error above the 32 bit address space
+                let request: std::collections::BTreeMap<String, u64> =
+                    serde_cbor::from_slice(&data[request_cursor.position() as usize..]).unwrap();
+                let mut response_map = std::collections::BTreeMap::new();
+                if request["addr"] + request["len"] > 1 << 32 {
+                    response_map.insert("rc", serde_cbor::Value::Integer(NmpErr::EInvalid as i128));
+                } else {
+                    let bytes: Vec<u8> = (request["addr"]..request["addr"] + request["len"])
+                        .map(|addr| addr as u8)
+                        .collect();
+                    response_map.insert("data", serde_cbor::Value::Bytes(bytes));
+                }
+
+                let body = serde_cbor::to_vec(&response_map).unwrap();
+                let (encoded_response, _) = encode_request(
+                    &self.specs,
+                    NmpOp::ReadRsp,
+                    NmpGroup::PerUser,
+                    0,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
             _ => {
                 // Handle other cases or return an error
             }
@@ -291,3 +389,67 @@ impl SerialPort for TestSerialPort {
         Ok(())
     }
 }
+
+/// specs opening the simulated device
+#[cfg(test)]
+pub(crate) fn test_specs() -> SerialSpecs {
+    SerialSpecs {
+        device: "test".to_string(),
+        ..Default::default()
+    }
+}
+
+/// stream for tests over a `StreamPort`, answering each flushed request with
+/// the next prepared response, after the request itself if the console echoes
+#[cfg(test)]
+pub(crate) struct MockStream {
+    pub tx: Vec<u8>,
+    pub rx: Vec<u8>,
+    pub echo: bool,
+    pub responses: std::collections::VecDeque<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl MockStream {
+    /// a device that sends back `responses` in turn, nothing pending
+    pub fn new(responses: impl IntoIterator<Item = Vec<u8>>) -> MockStream {
+        MockStream {
+            tx: Vec::new(),
+            rx: Vec::new(),
+            echo: false,
+            responses: responses.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.rx.is_empty() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        let n = std::cmp::min(buf.len(), self.rx.len());
+        buf[..n].copy_from_slice(&self.rx[..n]);
+        self.rx.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tx.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.echo {
+            self.rx.append(&mut self.tx);
+        }
+        self.tx.clear();
+        if let Some(response) = self.responses.pop_front() {
+            self.rx.extend(response);
+        }
+        Ok(())
+    }
+}
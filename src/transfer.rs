@@ -2,22 +2,62 @@
 
 use anyhow::{bail, Context, Error, Result};
 use base64::{engine::general_purpose, Engine as _};
-use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
-use crc16::*;
-use hex;
+use byteorder::{BigEndian, ByteOrder};
 use lazy_static::lazy_static;
 use log::debug;
 use rand::{thread_rng, Rng};
-use serde_cbor;
-use serialport::SerialPort;
+use serde::Serialize;
+use serde_cbor::Value;
+use serialport::{DataBits, FlowControl, Parity, SerialPort, StopBits};
 use std::cmp::min;
-use std::io::Cursor;
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU8, Ordering};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::nmp_hdr::*;
+use crate::protocol::{
+    check_answer, decode_serial_frame, encode_body, encode_frame, encode_packet, McuMgrError,
+    CONTINUATION_MARKER, MIN_LINELENGTH, START_MARKER,
+};
+use crate::stream_port::StreamPort;
 use crate::test_serial_port::TestSerialPort;
 
+/// when `transceive_with_retry` sends a request again
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// number of retries after the first attempt
+    pub max_retries: u32,
+    /// wait before each retry
+    pub backoff: Duration,
+    /// also retry when the response has a wrong checksum
+    pub retry_on_crc: bool,
+}
+
+impl From<&SerialSpecs> for RetryPolicy {
+    fn from(specs: &SerialSpecs) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: specs.nb_retry,
+            backoff: Duration::ZERO,
+            retry_on_crc: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// whether a request which failed with `e` is sent again, after `retries`
+    /// retries so far
+    pub fn should_retry(&self, retries: u32, e: &Error) -> bool {
+        retries < self.max_retries
+            && (is_timeout(e)
+                || (self.retry_on_crc && matches!(e.downcast_ref(), Some(McuMgrError::Crc))))
+    }
+}
+
+// how long resync drains input before the device is considered settled
+const RESYNC_WINDOW: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
 pub struct SerialSpecs {
     pub device: String,
     pub initial_timeout_s: u32,
@@ -25,17 +65,113 @@ pub struct SerialSpecs {
     pub nb_retry: u32,
     pub linelength: usize,
     pub mtu: usize,
-    pub baudrate: u32
+    pub baudrate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    pub start_marker: [u8; 2],
+    pub continuation_marker: [u8; 2],
+    pub resync: bool,
+    pub reset_delay_ms: Option<u32>,
+    pub canonical_cbor: bool,
+    pub response_delay_ms: u64,
+    pub expect_prompt: Option<String>,
+    pub prompt_timeout: Duration,
+}
+
+impl Default for SerialSpecs {
+    fn default() -> SerialSpecs {
+        SerialSpecs {
+            device: String::new(),
+            initial_timeout_s: 60,
+            subsequent_timeout_ms: 200,
+            nb_retry: 4,
+            linelength: 128,
+            mtu: 512,
+            baudrate: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None,
+            start_marker: START_MARKER,
+            continuation_marker: CONTINUATION_MARKER,
+            resync: false,
+            reset_delay_ms: None,
+            canonical_cbor: false,
+            response_delay_ms: 0,
+            expect_prompt: None,
+            prompt_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl SerialSpecs {
+    /// check the settings before opening the port
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.baudrate == 0 {
+            bail!("baudrate must be > 0");
+        }
+        if self.mtu <= NMP_HDR_LEN {
+            bail!("mtu must be > {}", NMP_HDR_LEN);
+        }
+        if self.linelength < MIN_LINELENGTH {
+            bail!("linelength must be >= {}", MIN_LINELENGTH);
+        }
+        if self.initial_timeout_s == 0 || self.subsequent_timeout_ms == 0 {
+            bail!("timeouts must be > 0");
+        }
+        Ok(())
+    }
 }
 
 fn read_byte(port: &mut dyn SerialPort) -> Result<u8, Error> {
     let mut byte = [0u8];
-    port.read(&mut byte)?;
+    port.read_exact(&mut byte).map_err(McuMgrError::from)?;
     Ok(byte[0])
 }
 
-fn expect_byte(port: &mut dyn SerialPort, b: u8) -> Result<(), Error> {
-    let read = read_byte(port)?;
+// error of a port operation, an unplugged device reports no device
+fn port_error(e: serialport::Error) -> McuMgrError {
+    if e.kind() == serialport::ErrorKind::NoDevice {
+        McuMgrError::DeviceDisconnected(e.into())
+    } else {
+        std::io::Error::from(e).into()
+    }
+}
+
+// Total time allowed for an exchange. Each frame is read with at most the
+// port timeout, cut to the time left when the frame starts.
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    read_timeout: Duration,
+}
+
+impl Deadline {
+    // time left, a timeout error once it has passed
+    fn left(&self) -> Result<Duration, Error> {
+        let left = self.at.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(McuMgrError::Timeout.into());
+        }
+        Ok(left)
+    }
+}
+
+fn read_byte_by(port: &mut dyn SerialPort, deadline: Option<&Deadline>) -> Result<u8, Error> {
+    if let Some(deadline) = deadline {
+        deadline.left()?;
+    }
+    read_byte(port)
+}
+
+fn expect_byte(port: &mut dyn SerialPort, b: u8, deadline: Option<&Deadline>) -> Result<(), Error> {
+    let read = read_byte_by(port, deadline)?;
     if read != b {
         bail!("read error, expected: {}, read: {}", b, read);
     }
@@ -43,14 +179,89 @@ fn expect_byte(port: &mut dyn SerialPort, b: u8) -> Result<(), Error> {
 }
 
 pub fn open_port(specs: &SerialSpecs) -> Result<Box<dyn SerialPort>, Error> {
-    if specs.device.to_lowercase() == "test" {
-        Ok(Box::new(TestSerialPort::new()))
+    specs.validate()?;
+    let mut port: Box<dyn SerialPort> = if specs.device.to_lowercase() == "test" {
+        Box::new(TestSerialPort::new(specs))
+    } else if let Some(addr) = specs.device.strip_prefix("tcp://") {
+        let timeout = Duration::from_secs(specs.initial_timeout_s as u64);
+        Box::new(StreamPort::connect_tcp(addr, timeout)?)
     } else {
         serialport::new(&specs.device, specs.baudrate)
+            .data_bits(specs.data_bits)
+            .parity(specs.parity)
+            .stop_bits(specs.stop_bits)
+            .flow_control(specs.flow_control)
             .timeout(Duration::from_secs(specs.initial_timeout_s as u64))
             .open()
-            .with_context(|| format!("failed to open serial port {}", &specs.device))
+            .with_context(|| format!("failed to open serial port {}", &specs.device))?
+    };
+    if let Some(level) = specs.dtr {
+        port.write_data_terminal_ready(level)?;
+    }
+    if let Some(level) = specs.rts {
+        port.write_request_to_send(level)?;
+    }
+    if let Some(prompt) = &specs.expect_prompt {
+        expect_prompt(&mut *port, prompt, specs.prompt_timeout)?;
+    }
+    if specs.resync {
+        resync(&mut *port)?;
     }
+    Ok(port)
+}
+
+// Wait for the banner or prompt some devices print once the port is open,
+// before they accept requests. Everything up to the prompt is consumed.
+pub fn expect_prompt(
+    port: &mut dyn SerialPort,
+    prompt: &str,
+    timeout: Duration,
+) -> Result<(), Error> {
+    debug!("waiting for prompt {:?}", prompt);
+    let port_timeout = port.timeout();
+    port.set_timeout(timeout)?;
+    let deadline = Instant::now() + timeout;
+    let mut received = Vec::new();
+    let found = loop {
+        if received.ends_with(prompt.as_bytes()) {
+            break true;
+        }
+        if Instant::now() >= deadline {
+            break false;
+        }
+        match read_byte(&mut *port) {
+            Ok(b) => received.push(b),
+            Err(e) if is_timeout(&e) => (),
+            Err(e) => return Err(e),
+        }
+    };
+    port.set_timeout(port_timeout)?;
+    if !found {
+        bail!(
+            "no prompt {:?} from the device within {}",
+            prompt,
+            humantime::format_duration(timeout)
+        );
+    }
+    debug!("prompt received after {} bytes", received.len());
+    Ok(())
+}
+
+// terminate a partial frame left over from an aborted session, then drain
+// whatever the device sends back until it has settled
+pub fn resync(port: &mut dyn SerialPort) -> Result<(), Error> {
+    debug!("resync");
+    port.write_all(b"\n")?;
+    port.flush()?;
+    let deadline = Instant::now() + RESYNC_WINDOW;
+    while Instant::now() < deadline {
+        let to_read = port.bytes_to_read()?;
+        for _ in 0..to_read {
+            read_byte(&mut *port)?;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
 }
 
 // thread-safe counter, initialized with a random value on first call
@@ -62,91 +273,232 @@ pub fn next_seq_id() -> u8 {
 }
 
 pub fn encode_request(
-    linelength: usize,
+    specs: &SerialSpecs,
     op: NmpOp,
     group: NmpGroup,
     id: impl NmpId,
-    body: &Vec<u8>,
+    body: &[u8],
     seq_id: u8,
 ) -> Result<(Vec<u8>, NmpHdr), Error> {
-    // create request
-    let mut request_header = NmpHdr::new_req(op, group, id);
-    request_header.seq = seq_id;
-    request_header.len = body.len() as u16;
-    debug!("request header: {:?}", request_header);
-    let mut serialized = request_header.serialize()?;
-    serialized.extend(body);
-    debug!("serialized: {}", hex::encode(&serialized));
-
-    // calculate CRC16 of it and append to the request
-    let checksum = State::<XMODEM>::calculate(&serialized);
-    serialized.write_u16::<BigEndian>(checksum)?;
-
-    // prepend chunk length
-    let mut len: Vec<u8> = Vec::new();
-    len.write_u16::<BigEndian>(serialized.len() as u16)?;
-    serialized.splice(0..0, len);
-    debug!(
-        "encoded with packet length and checksum: {}",
-        hex::encode(&serialized)
-    );
-
-    // convert to base64
-    let base64_data: Vec<u8> = general_purpose::STANDARD.encode(&serialized).into_bytes();
-    debug!("encoded: {}", String::from_utf8(base64_data.clone())?);
-    let mut data = Vec::<u8>::new();
-
-    // transfer in blocks of max linelength bytes per line
-    let mut written = 0;
-    let totlen = base64_data.len();
-    while written < totlen {
-        // start designator
-        if written == 0 {
-            data.extend_from_slice(&[6, 9]);
-        } else {
-            // TODO: add a configurable sleep for slower devices
-            // thread::sleep(Duration::from_millis(20));
-            data.extend_from_slice(&[4, 20]);
+    if specs.mtu <= NMP_HDR_LEN {
+        bail!("mtu must be > {}", NMP_HDR_LEN);
+    }
+    let (packet, request_header) = encode_packet(op, group, id, body, seq_id)?;
+    let data = encode_frame(
+        &packet,
+        specs.linelength,
+        specs.start_marker,
+        specs.continuation_marker,
+    )?;
+    Ok((data, request_header))
+}
+
+// whether the error is a read or write timeout on the port
+pub fn is_timeout(e: &Error) -> bool {
+    if let Some(McuMgrError::Timeout) = e.downcast_ref::<McuMgrError>() {
+        return true;
+    }
+    match e.downcast_ref::<std::io::Error>() {
+        Some(e) => e.kind() == std::io::ErrorKind::TimedOut,
+        None => false,
+    }
+}
+
+/// Send a request and wait for the matching response. The request is sent
+/// again with the same sequence number after a timeout, or a wrong checksum
+/// if the policy allows it.
+pub fn transceive_with_retry(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    req: &impl Serialize,
+    policy: &RetryPolicy,
+) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    let rsp = transceive_with_retry_raw(port, specs, op, group, id, req, policy)?;
+    Ok((rsp.header, rsp.body))
+}
+
+/// `transceive_with_retry` with a request body filled in by `build`, for
+/// one-off commands which don't need a request struct
+pub fn transceive_with<F>(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    build: F,
+    policy: &RetryPolicy,
+) -> Result<(NmpHdr, Value), Error>
+where
+    F: FnOnce(&mut BTreeMap<Value, Value>),
+{
+    let mut req = BTreeMap::new();
+    build(&mut req);
+    transceive_with_retry(port, specs, op, group, id, &Value::Map(req), policy)
+}
+
+/// `transceive_with_retry`, also returning the response frame as received
+pub fn transceive_with_retry_raw(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    req: &impl Serialize,
+    policy: &RetryPolicy,
+) -> Result<RawResponse, Error> {
+    let body = encode_body(req, specs.canonical_cbor)?;
+    let (data, request_header) = encode_request(specs, op, group, id, &body, next_seq_id())?;
+
+    let mut retries = 0;
+    loop {
+        match transceive_raw(&mut *port, specs, &data) {
+            Ok(rsp) => {
+                if !check_answer(&request_header, &rsp.header) {
+                    bail!("wrong answer types")
+                }
+                return Ok(rsp);
+            }
+            Err(e) if policy.should_retry(retries, &e) => {
+                retries += 1;
+                debug!("{}, retry {} of {}", e, retries, policy.max_retries);
+                thread::sleep(policy.backoff);
+            }
+            Err(e) => return Err(e),
         }
-        let write_len = min(linelength - 4, totlen - written);
-        data.extend_from_slice(&base64_data[written..written + write_len]);
-        data.push(b'\n');
-        written += write_len;
     }
+}
 
-    Ok((data, request_header))
+/// response with the frame it was decoded from, for debugging
+#[derive(Debug, Clone)]
+pub struct RawResponse {
+    pub header: NmpHdr,
+    pub body: serde_cbor::Value,
+    /// decoded frame: length, NMP header, CBOR body and checksum
+    pub frame: Vec<u8>,
 }
 
 pub fn transceive(
     port: &mut dyn SerialPort,
-    data: &Vec<u8>,
+    specs: &SerialSpecs,
+    data: &[u8],
 ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    let rsp = transceive_raw(port, specs, data)?;
+    Ok((rsp.header, rsp.body))
+}
+
+pub fn transceive_raw(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    data: &[u8],
+) -> Result<RawResponse, Error> {
+    transceive_raw_by(port, specs, data, None)
+}
+
+/// `transceive` bounded by `deadline` for the whole exchange, however many
+/// frames the response takes. Fails with `McuMgrError::Timeout` once it has
+/// passed. The port timeout still applies to each read.
+pub fn transceive_until(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    data: &[u8],
+    deadline: Instant,
+) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    let read_timeout = port.timeout();
+    let deadline = Deadline {
+        at: deadline,
+        read_timeout,
+    };
+    let result = transceive_raw_by(&mut *port, specs, data, Some(&deadline));
+    port.set_timeout(read_timeout)?;
+    let rsp = result?;
+    Ok((rsp.header, rsp.body))
+}
+
+fn transceive_raw_by(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    data: &[u8],
+    deadline: Option<&Deadline>,
+) -> Result<RawResponse, Error> {
+    if let Some(deadline) = deadline {
+        deadline.left()?;
+    }
+
     // empty input buffer
-    let to_read = port.bytes_to_read()?;
+    let to_read = port.bytes_to_read().map_err(port_error)?;
     for _ in 0..to_read {
         read_byte(&mut *port)?;
     }
 
-    // write request
-    port.write_all(data)?;
+    // write request, some USB serial drivers hold it until flushed
+    port.write_all(data).map_err(McuMgrError::from)?;
+    port.flush().map_err(McuMgrError::from)?;
+
+    // slow devices send stale bytes if polled right away
+    if specs.response_delay_ms > 0 {
+        let mut delay = Duration::from_millis(specs.response_delay_ms);
+        if let Some(deadline) = deadline {
+            delay = min(delay, deadline.left()?);
+        }
+        thread::sleep(delay);
+    }
+
+    // a console with local echo sends the request back before the response
+    let request_seq = request_seq(data);
+    loop {
+        let decoded = read_frame(&mut *port, specs, deadline)?;
+        let (header, body) = decode_serial_frame(&decoded)?;
+        if matches!(header.op, NmpOp::Read | NmpOp::Write) && Some(header.seq) == request_seq {
+            debug!("skipping echoed request");
+            continue;
+        }
+        return Ok(RawResponse {
+            header,
+            body,
+            frame: decoded,
+        });
+    }
+}
 
-    // read result
+// sequence number of an encoded request
+fn request_seq(data: &[u8]) -> Option<u8> {
+    let text: Vec<u8> = data
+        .split(|b| *b == b'\n')
+        .filter(|line| line.len() > 2)
+        .flat_map(|line| line[2..].iter().copied())
+        .collect();
+    let decoded = general_purpose::STANDARD.decode(text).ok()?;
+    decoded.get(2 + NmpHdr::SEQ_OFFSET).copied()
+}
+
+// read one frame, and return it without the markers and base64 encoding
+fn read_frame(
+    port: &mut dyn SerialPort,
+    specs: &SerialSpecs,
+    deadline: Option<&Deadline>,
+) -> Result<Vec<u8>, Error> {
+    if let Some(deadline) = deadline {
+        port.set_timeout(min(deadline.left()?, deadline.read_timeout))?;
+    }
     let mut bytes_read = 0;
     let mut expected_len = 0;
     let mut result: Vec<u8> = Vec::new();
     loop {
         // first wait for the chunk start marker
         if bytes_read == 0 {
-            expect_byte(&mut *port, 6)?;
-            expect_byte(&mut *port, 9)?;
+            expect_byte(&mut *port, specs.start_marker[0], deadline)?;
+            expect_byte(&mut *port, specs.start_marker[1], deadline)?;
         } else {
-            expect_byte(&mut *port, 4)?;
-            expect_byte(&mut *port, 20)?;
+            expect_byte(&mut *port, specs.continuation_marker[0], deadline)?;
+            expect_byte(&mut *port, specs.continuation_marker[1], deadline)?;
         }
 
         // next read until newline
         loop {
-            let b = read_byte(&mut *port)?;
+            let b = read_byte_by(&mut *port, deadline)?;
             if b == 0xa {
                 break;
             } else {
@@ -157,6 +509,10 @@ pub fn transceive(
 
         // try to extract length
         let decoded: Vec<u8> = general_purpose::STANDARD.decode(&result)?;
+        if decoded.len() < 2 {
+            // too short to hold the length, the frame check reports it
+            break;
+        }
         if expected_len == 0 {
             let len = BigEndian::read_u16(&decoded);
             if len > 0 {
@@ -172,39 +528,15 @@ pub fn transceive(
     }
 
     // decode base64
-    debug!("result string: {}", String::from_utf8(result.clone())?);
+    debug!("result string: {}", String::from_utf8_lossy(&result));
     let decoded: Vec<u8> = general_purpose::STANDARD.decode(&result)?;
-
-    // verify length: must be the decoded length, minus the 2 bytes to encode the length
-    let len = BigEndian::read_u16(&decoded) as usize;
-    if len != decoded.len() - 2 {
-        bail!("wrong chunk length");
-    }
-
-    // verify checksum
-    let data = decoded[2..decoded.len() - 2].to_vec();
-    let read_checksum = BigEndian::read_u16(&decoded[decoded.len() - 2..]);
-    let calculated_checksum = State::<XMODEM>::calculate(&data);
-    if read_checksum != calculated_checksum {
-        bail!("wrong checksum");
-    }
-
-    // read header
-    let mut cursor = Cursor::new(&data);
-    let response_header = NmpHdr::deserialize(&mut cursor).unwrap();
-    debug!("response header: {:?}", response_header);
-
-    debug!("cbor: {}", hex::encode(&data[8..]));
-
-    // decode body in CBOR format
-    let body = serde_cbor::from_reader(cursor)?;
-
-    Ok((response_header, body))
+    Ok(decoded)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::next_seq_id;
+    use super::*;
+    use crate::test_serial_port::{test_specs, MockStream};
     use std::collections::HashSet;
 
     #[test]
@@ -213,7 +545,7 @@ mod tests {
         let initial_id = next_seq_id();
         ids.insert(initial_id);
 
-        for _ in 0..std::u8::MAX {
+        for _ in 0..u8::MAX {
             let id = next_seq_id();
             assert!(ids.insert(id), "Duplicate ID: {}", id);
         }
@@ -225,4 +557,241 @@ mod tests {
             "Wrapped ID does not match initial ID"
         );
     }
+
+    #[test]
+    fn test_custom_markers() {
+        let specs = SerialSpecs {
+            linelength: 8,
+            start_marker: [1, 2],
+            continuation_marker: [3, 4],
+            ..test_specs()
+        };
+        let mut port = open_port(&specs).unwrap();
+
+        let body = serde_cbor::to_vec(&BTreeMap::<String, String>::new()).unwrap();
+        let (data, request_header) = encode_request(
+            &specs,
+            NmpOp::Read,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            &body,
+            42,
+        )
+        .unwrap();
+        assert_eq!(data[..2], [1, 2]);
+        assert!(data.windows(2).any(|w| w == [3, 4]));
+        assert!(!data.windows(2).any(|w| w == START_MARKER));
+
+        let (response_header, _) = transceive(&mut *port, &specs, &data).unwrap();
+        assert_eq!(response_header.seq, request_header.seq);
+        assert_eq!(response_header.op, NmpOp::ReadRsp);
+    }
+
+    #[test]
+    fn test_validate() {
+        let specs = test_specs();
+        assert!(specs.validate().is_ok());
+
+        let invalid = [
+            SerialSpecs {
+                baudrate: 0,
+                ..specs.clone()
+            },
+            SerialSpecs {
+                mtu: 8,
+                ..specs.clone()
+            },
+            SerialSpecs {
+                linelength: 4,
+                ..specs.clone()
+            },
+            SerialSpecs {
+                subsequent_timeout_ms: 0,
+                ..specs.clone()
+            },
+        ];
+        for specs in invalid.iter() {
+            assert!(specs.validate().is_err());
+            assert!(open_port(specs).is_err());
+        }
+    }
+
+    #[test]
+    fn test_raw_response() {
+        let specs = test_specs();
+        let mut port = open_port(&specs).unwrap();
+        let rsp = transceive_with_retry_raw(
+            &mut *port,
+            &specs,
+            NmpOp::Read,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            &BTreeMap::<String, String>::new(),
+            &RetryPolicy::from(&specs),
+        )
+        .unwrap();
+        assert_eq!(
+            rsp.frame.len(),
+            2 + NMP_HDR_LEN + rsp.header.payload_len() + 2
+        );
+        let body: serde_cbor::Value =
+            serde_cbor::from_slice(&rsp.frame[2 + NMP_HDR_LEN..rsp.frame.len() - 2]).unwrap();
+        assert_eq!(body, rsp.body);
+    }
+
+    #[test]
+    fn test_transceive_with() {
+        let specs = test_specs();
+        let mut port = open_port(&specs).unwrap();
+        let (header, _) = transceive_with(
+            &mut *port,
+            &specs,
+            NmpOp::Write,
+            NmpGroup::Image,
+            NmpIdImage::Erase,
+            |req| {
+                req.insert(Value::Text("slot".to_string()), Value::Integer(1));
+            },
+            &RetryPolicy::from(&specs),
+        )
+        .unwrap();
+        assert_eq!(header.op, NmpOp::WriteRsp);
+    }
+
+    #[test]
+    fn test_skip_echo() {
+        let specs = SerialSpecs::default();
+        let body = serde_cbor::to_vec(&BTreeMap::<String, String>::new()).unwrap();
+        let encode = |op| {
+            let (data, _) =
+                encode_request(&specs, op, NmpGroup::Image, NmpIdImage::State, &body, 9).unwrap();
+            data
+        };
+        let request = encode(NmpOp::Read);
+        // console with local echo, sending back the request before the response
+        let echo = MockStream {
+            echo: true,
+            ..MockStream::new([encode(NmpOp::ReadRsp)])
+        };
+        let mut port = StreamPort::new(echo, Duration::from_secs(1));
+        let rsp = transceive_raw(&mut port, &specs, &request).unwrap();
+        assert_eq!(rsp.header.op, NmpOp::ReadRsp);
+        assert_eq!(rsp.header.seq, 9);
+    }
+
+    #[test]
+    fn test_expect_prompt() {
+        let console = |rx: &[u8]| MockStream {
+            rx: rx.to_vec(),
+            ..MockStream::new([])
+        };
+        let timeout = Duration::from_millis(50);
+        let mut port = StreamPort::new(console(b"*** Booting ***\nuart:~$ \x06\x09"), timeout);
+        expect_prompt(&mut port, "uart:~$ ", timeout).unwrap();
+        assert_eq!(port.into_inner().rx, [6, 9]);
+
+        let mut port = StreamPort::new(console(b"*** Booting ***\n"), timeout);
+        let e = expect_prompt(&mut port, "uart:~$ ", timeout).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "no prompt \"uart:~$ \" from the device within 50ms"
+        );
+    }
+
+    // console sending a frame start and then a line that never ends, a byte
+    // at a time, so no single read times out
+    struct Trickle {
+        sent: usize,
+    }
+
+    impl std::io::Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(5));
+            buf[0] = match self.sent {
+                0 => 6,
+                1 => 9,
+                _ => b'A',
+            };
+            self.sent += 1;
+            Ok(1)
+        }
+    }
+
+    impl std::io::Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transceive_until() {
+        let specs = SerialSpecs::default();
+        let (data, _) = encode_request(
+            &specs,
+            NmpOp::Read,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            &[0xa0],
+            1,
+        )
+        .unwrap();
+        let mut port = StreamPort::new(Trickle { sent: 0 }, Duration::from_secs(1));
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        let e = transceive_until(&mut port, &specs, &data, deadline).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(McuMgrError::Timeout)));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(port.timeout(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_response_delay() {
+        let specs = SerialSpecs {
+            response_delay_ms: 50,
+            ..test_specs()
+        };
+        let mut port = open_port(&specs).unwrap();
+        let start = Instant::now();
+        transceive_with_retry(
+            &mut *port,
+            &specs,
+            NmpOp::Read,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            &BTreeMap::<String, String>::new(),
+            &RetryPolicy::from(&specs),
+        )
+        .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_invalid_linelength() {
+        let mut specs = SerialSpecs {
+            linelength: 3,
+            ..Default::default()
+        };
+        let encode = |specs: &SerialSpecs| {
+            encode_request(
+                specs,
+                NmpOp::Read,
+                NmpGroup::Image,
+                NmpIdImage::State,
+                &[],
+                0,
+            )
+        };
+        let e = encode(&specs).unwrap_err();
+        assert_eq!(e.to_string(), "linelength must be >= 8");
+
+        specs.linelength = 8;
+        assert!(encode(&specs).is_ok());
+
+        specs.mtu = 4;
+        assert!(encode(&specs).is_err());
+    }
 }
@@ -0,0 +1,126 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, format_err, Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+use std::str::FromStr;
+
+/// magic number at the start of an MCUboot image header
+pub const MCUBOOT_IMAGE_MAGIC: u32 = 0x96f3b83d;
+
+// offset of the version in the MCUboot image header
+const MCUBOOT_VERSION_OFFSET: usize = 20;
+
+/// MCUboot image version, ordered by major, minor, revision and build number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build: u32,
+}
+
+impl ImageVersion {
+    /// read the version from the header at the start of an MCUboot image
+    pub fn from_mcuboot_header(data: &[u8]) -> Result<ImageVersion, Error> {
+        if data.len() < MCUBOOT_VERSION_OFFSET + 8 {
+            bail!("image too short for an MCUboot header");
+        }
+        if LittleEndian::read_u32(data) != MCUBOOT_IMAGE_MAGIC {
+            bail!("not an MCUboot image, wrong header magic");
+        }
+        let ver = &data[MCUBOOT_VERSION_OFFSET..];
+        Ok(ImageVersion {
+            major: ver[0],
+            minor: ver[1],
+            revision: LittleEndian::read_u16(&ver[2..]),
+            build: LittleEndian::read_u32(&ver[4..]),
+        })
+    }
+}
+
+impl FromStr for ImageVersion {
+    type Err = Error;
+
+    /// parse "major.minor.revision" with an optional ".build" or "+build",
+    /// as reported in the `version` of an image state entry
+    fn from_str(s: &str) -> Result<ImageVersion, Error> {
+        let parts: Vec<&str> = s.trim().split(['.', '+']).collect();
+        if parts.len() < 3 || parts.len() > 4 {
+            bail!("invalid image version: {}", s);
+        }
+        let field = |i: usize| -> Result<u32, Error> {
+            parts[i]
+                .parse()
+                .map_err(|_| format_err!("invalid image version: {}", s))
+        };
+        let narrow = |v: u32, max: u32| -> Result<u32, Error> {
+            if v > max {
+                bail!("invalid image version: {}", s);
+            }
+            Ok(v)
+        };
+        Ok(ImageVersion {
+            major: narrow(field(0)?, u8::MAX as u32)? as u8,
+            minor: narrow(field(1)?, u8::MAX as u32)? as u8,
+            revision: narrow(field(2)?, u16::MAX as u32)? as u16,
+            build: if parts.len() == 4 { field(3)? } else { 0 },
+        })
+    }
+}
+
+impl fmt::Display for ImageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.revision)?;
+        if self.build != 0 {
+            write!(f, ".{}", self.build)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_order() {
+        let v: ImageVersion = "1.2.3".parse().unwrap();
+        assert_eq!(
+            v,
+            ImageVersion {
+                major: 1,
+                minor: 2,
+                revision: 3,
+                build: 0
+            }
+        );
+        assert_eq!("1.2.3.4".parse::<ImageVersion>().unwrap().build, 4);
+        assert_eq!("1.2.3+4".parse::<ImageVersion>().unwrap().build, 4);
+        assert!("1.2".parse::<ImageVersion>().is_err());
+        assert!("256.0.0".parse::<ImageVersion>().is_err());
+
+        assert!(v < "1.2.3.1".parse().unwrap());
+        assert!(v < "1.10.0".parse().unwrap());
+        assert!(v > "0.9.9".parse().unwrap());
+        assert_eq!(v.to_string(), "1.2.3");
+    }
+
+    #[test]
+    fn test_mcuboot_header() {
+        let mut header = vec![0u8; 32];
+        LittleEndian::write_u32(&mut header, MCUBOOT_IMAGE_MAGIC);
+        header[20] = 2;
+        header[21] = 1;
+        LittleEndian::write_u16(&mut header[22..], 300);
+        LittleEndian::write_u32(&mut header[24..], 7);
+        assert_eq!(
+            ImageVersion::from_mcuboot_header(&header).unwrap(),
+            "2.1.300.7".parse().unwrap()
+        );
+
+        header[0] = 0;
+        assert!(ImageVersion::from_mcuboot_header(&header).is_err());
+        assert!(ImageVersion::from_mcuboot_header(&header[..16]).is_err());
+    }
+}